@@ -0,0 +1,89 @@
+//! Load generator for periodic `StreamDeviceData` clients.
+//!
+//! Opens many streams on the same refresh interval against a running service, counts the responses they get,
+//! and reports how much CPU time the service spent in the meantime. For example, against 50 simulated devices:
+//!
+//! ```shell
+//! cargo build --release && ./target/release/govee_collector --simulate simulation.toml &
+//! cargo run --release --example stream_load -- --clients 200 --duration 30 --server-pid $!
+//! ```
+
+use std::error::Error;
+use std::fs;
+use std::time::Duration;
+
+use futures::StreamExt;
+use structopt::StructOpt;
+use tokio::time::{Instant, timeout_at};
+
+use govee_collector::device_data_provider_client::DeviceDataProviderClient;
+use govee_collector::StreamDeviceDataRequest;
+
+pub mod govee_collector {
+    tonic::include_proto!("govee_collector");
+}
+
+#[derive(StructOpt)]
+#[structopt(about = "Opens many periodic StreamDeviceData clients against a running service")]
+struct Opt {
+    #[structopt(short, long, default_value = "127.0.0.1:50051", help = "Socket address of the service")]
+    address: String,
+
+    #[structopt(short, long, default_value = "200", help = "How many streams to open")]
+    clients: usize,
+
+    #[structopt(short, long, default_value = "30", help = "How long to keep the streams open (in seconds)")]
+    duration: u64,
+
+    #[structopt(long, default_value = "1", help = "Refresh interval of every stream (in seconds)")]
+    refresh_interval: u32,
+
+    #[structopt(long, help = "Process id of the service, to report the CPU time it spent (Linux only)")]
+    server_pid: Option<u32>,
+}
+
+/// Clock ticks per second of the times in `/proc`, which is 100 on every mainstream Linux architecture.
+const USER_HZ: u64 = 100;
+
+/// Time all the threads of the process spent on the CPU so far, in user and kernel mode.
+fn cpu_time(pid: u32) -> Result<Duration, Box<dyn Error>> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid))?;
+    // the command name is in parentheses and might contain spaces, so the fields are counted after it
+    let fields: Vec<&str> = stat.rsplit_once(')').ok_or("malformed stat")?.1.split_whitespace().collect();
+    let ticks = |index: usize| -> Result<u64, Box<dyn Error>> { Ok(fields.get(index).ok_or("malformed stat")?.parse()?) };
+    let ticks = ticks(11)? + ticks(12)?;
+    Ok(Duration::from_millis(ticks * 1000 / USER_HZ))
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let opt = Opt::from_args();
+    let client = DeviceDataProviderClient::connect(format!("http://{}", opt.address)).await?;
+    let mut streams = vec![];
+    for _ in 0..opt.clients {
+        let request = StreamDeviceDataRequest { refresh_interval_in_secs: Some(opt.refresh_interval), ..Default::default() };
+        streams.push(client.clone().stream_device_data(request).await?.into_inner());
+    }
+    println!("Opened {} streams", streams.len());
+
+    let cpu_time_before = opt.server_pid.map(cpu_time).transpose()?;
+    let deadline = Instant::now() + Duration::from_secs(opt.duration);
+    let clients: Vec<_> = streams.into_iter()
+        .map(|mut stream| tokio::spawn(async move {
+            let mut responses = 0;
+            while let Ok(Some(Ok(_))) = timeout_at(deadline, stream.next()).await {
+                responses += 1;
+            }
+            responses
+        }))
+        .collect();
+    let mut responses = 0;
+    for client in clients {
+        responses += client.await?;
+    }
+    println!("Received {} responses in {} seconds", responses, opt.duration);
+    if let (Some(pid), Some(before)) = (opt.server_pid, cpu_time_before) {
+        println!("Service spent {} ms on the CPU", (cpu_time(pid)? - before).as_millis());
+    }
+    Ok(())
+}
@@ -0,0 +1,52 @@
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Writes the contents next to the file first, so that a crash can't leave a truncated file behind.
+///
+/// The contents are synced to the disk before they are renamed over the file, as otherwise the rename
+/// could make it there first.
+pub fn write_atomically(path: &Path, contents: &str) -> io::Result<()> {
+    // replace the target of a symlink, rather than the symlink itself
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let file_name = path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file name"))?;
+    // e.g. .state.json.tmp, which can't be mistaken for a file that only shares the stem, like state.tmp
+    let mut temporary_name = OsString::from(".");
+    temporary_name.push(file_name);
+    temporary_name.push(".tmp");
+    let temporary_path = path.with_file_name(temporary_name);
+    let mut file = File::create(&temporary_path)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temporary_path, &path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_files_are_replaced_without_leftovers() {
+        let temporary_directory = tempfile::tempdir().unwrap();
+        let directory = temporary_directory.path();
+        let path = directory.join("state.json");
+        fs::write(directory.join("state.tmp"), "unrelated").unwrap();
+        write_atomically(&path, "old").unwrap();
+        write_atomically(&path, "new").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(fs::read_to_string(directory.join("state.tmp")).unwrap(), "unrelated");
+        let mut names: Vec<_> = fs::read_dir(directory).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        names.sort();
+        assert_eq!(names, vec!["state.json", "state.tmp"]);
+
+        #[cfg(unix)]
+        {
+            let link = directory.join("link.json");
+            std::os::unix::fs::symlink(&path, &link).unwrap();
+            write_atomically(&link, "newer").unwrap();
+            assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+            assert_eq!(fs::read_to_string(&path).unwrap(), "newer");
+        }
+    }
+}
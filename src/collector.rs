@@ -1,22 +1,99 @@
 use std::collections::HashMap;
 use std::error::Error;
-use std::sync::Arc;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
-use btleplug::api::{Central, CentralEvent, Manager as _, Peripheral, ScanFilter};
-use btleplug::platform::{Adapter, Manager, PeripheralId};
+use futures::Stream;
 use futures::stream::StreamExt;
-use tokio::sync::RwLock;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, RwLock};
+use tokio::task::spawn_blocking;
+use tokio::time::{interval, sleep};
 
-use crate::collector::govee_h5075::DeviceData;
-use crate::device_database::DeviceDatabase;
+use crate::collector::govee_h5075::{ADVERTISEMENT_INTERVAL, DeviceData, DeviceDataError};
+use crate::collector::link_statistics::LinkStatistics;
+use crate::collector::liveness::LivenessTracker;
+use crate::collector::readings::{DerivedData, READINGS_CAPACITY, REPLAY_BUFFER_CAPACITY, ReadingData, ReplayBuffer};
+use crate::collector::recent::RecentReadings;
+use crate::collector::state::SavedReading;
+use crate::retry::{retry, Backoff};
+use crate::snapshot::SnapshotMap;
+use crate::device_database::{DeviceDatabase, Quantity};
 
+pub use bluetooth::BluetoothSource;
+pub use capture::RecordingSource;
+pub use link_statistics::{LinkSummary, WindowStatistics};
+pub use liveness::{Liveness, LivenessChange};
+pub use readings::{Reading, ReadingSubscriber};
+pub use recent::{Extremes, RecentReading};
+pub use replay::{ReplaySource, ReplaySpeed};
+pub use simulator::SimulatedSource;
+pub use state::SavedState;
+
+mod bluetooth;
+mod btsnoop;
+mod capture;
 mod govee_h5075;
+mod link_statistics;
+mod liveness;
+mod readings;
+mod recent;
+mod replay;
+mod simulator;
+mod state;
+
+/// A single advertisement, as received from a peripheral (or read back from a capture).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Advertisement {
+    pub address: String,
+    pub local_name: Option<String>,
+    pub rssi: Option<i16>,
+    pub manufacturer_data: HashMap<u16, Vec<u8>>,
+    /// Set when the peripheral was just discovered, in which case the manufacturer data is whatever was last seen
+    /// rather than a freshly received packet, so it doesn't count towards the link statistics.
+    #[serde(default)]
+    pub discovered: bool,
+}
+
+pub type AdvertisementStream = Pin<Box<dyn Stream<Item = Advertisement> + Send>>;
+
+/// Anything that can feed advertisements into the [`Collector`].
+#[tonic::async_trait]
+pub trait AdvertisementSource: Send + Sync {
+    async fn advertisements(&self) -> Result<AdvertisementStream, Box<dyn Error>>;
+}
+
+/// What the collector is up to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CollectorState {
+    /// Waiting for the advertisement source, e.g. for the bluetooth adapter to become available.
+    Initializing,
+    Running,
+    /// The advertisement source is exhausted.
+    Stopped,
+}
+
+/// How long to wait before trying to start scanning again.
+const SCAN_BACKOFF: Backoff = Backoff::constant(Duration::from_secs(1));
+
+/// How often the state is saved while the service is running, besides when it's stopped.
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(60);
 
 pub struct Collector {
-    central: Adapter,
     device_database: Arc<DeviceDatabase>,
-    known_devices: RwLock<HashMap<PeripheralId, String>>,
-    device_data: RwLock<HashMap<String, DeviceData>>,
+    stale_after: Duration,
+    state: Mutex<CollectorState>,
+    // read by every API request, so they must never make advertisement processing wait (or the other way around)
+    known_devices: SnapshotMap<String, String>,
+    device_data: SnapshotMap<String, Reading>,
+    liveness: LivenessTracker,
+    link_statistics: RwLock<HashMap<String, LinkStatistics>>,
+    readings: broadcast::Sender<Reading>,
+    // only held for a push or a copy, never across an await
+    replay_buffer: Mutex<ReplayBuffer>,
+    recent_readings: Mutex<RecentReadings>,
 }
 
 #[derive(Debug, thiserror::Error, Eq, PartialEq)]
@@ -26,72 +103,338 @@ pub enum CollectorError {
 }
 
 impl Collector {
-    pub async fn new(device_database: Arc<DeviceDatabase>) -> Result<Collector, Box<dyn Error>> {
-        let manager = Manager::new().await?;
-
-        // get the first bluetooth adapter
-        let adapters = manager.adapters().await?;
-        match adapters.into_iter().nth(0) {
-            Some(central) => Ok(Collector {
-                central,
-                device_database,
-                known_devices: RwLock::new(HashMap::new()),
-                device_data: RwLock::new(HashMap::new()),
-            }),
-            None => Result::Err(Box::new(CollectorError::NoAdaptersFound))
+    /// Keeps the readings of every device within the recent readings window around, for short histories.
+    pub fn new(device_database: Arc<DeviceDatabase>, stale_after: Duration, recent_readings_window: Duration) -> Collector {
+        Collector {
+            device_database,
+            stale_after,
+            state: Mutex::new(CollectorState::Initializing),
+            known_devices: SnapshotMap::new(),
+            device_data: SnapshotMap::new(),
+            liveness: LivenessTracker::new(),
+            link_statistics: RwLock::new(HashMap::new()),
+            readings: broadcast::channel(READINGS_CAPACITY).0,
+            replay_buffer: Mutex::new(ReplayBuffer::new(REPLAY_BUFFER_CAPACITY)),
+            recent_readings: Mutex::new(RecentReadings::new(recent_readings_window)),
+        }
+    }
+
+    /// Like [`Collector::start`], but keeps trying until the deadline, as the bluetooth adapter
+    /// can show up a little before it's able to scan.
+    pub async fn start_retrying(&self, source: &dyn AdvertisementSource, deadline: tokio::time::Instant) -> Result<(), Box<dyn Error>> {
+        retry(SCAN_BACKOFF, deadline, "Unable to start scanning", || self.start(source)).await
+    }
+
+    pub async fn start(&self, source: &dyn AdvertisementSource) -> Result<(), Box<dyn Error>> {
+        let mut advertisements = source.advertisements().await?;
+        self.set_state(CollectorState::Running);
+        while let Some(advertisement) = advertisements.next().await {
+            self.process_advertisement(advertisement).await;
+        }
+        info!("Advertisement source is exhausted");
+        self.set_state(CollectorState::Stopped);
+        Ok(())
+    }
+
+    pub fn get_state(&self) -> CollectorState {
+        *self.state.lock().expect("Could not lock mutex")
+    }
+
+    fn set_state(&self, state: CollectorState) {
+        *self.state.lock().expect("Could not lock mutex") = state;
+    }
+
+    /// Periodically marks devices that stopped advertising as offline, and forgets their readings once they are no longer recent.
+    pub async fn monitor_liveness(&self) {
+        let mut ticks = interval(Duration::from_secs(1));
+        loop {
+            ticks.tick().await;
+            self.liveness.expire(|local_name| self.stale_after(local_name));
+            self.recent_readings.lock().expect("Could not lock mutex")
+                .expire(SystemTime::now(), |local_name| self.device_database.contains_device(local_name));
         }
     }
 
-    pub async fn start(&self) -> Result<(), Box<dyn Error>> {
-        let mut events = self.central.events().await?;
-        self.central.start_scan(ScanFilter::default()).await?;
-
-        while let Some(event) = events.next().await {
-            trace!("Received event {:?}", event);
-            match event {
-                CentralEvent::DeviceDiscovered(id) => {
-                    debug!("Discovered device {:?}", id);
-                    if let Ok(peripheral) = self.central.peripheral(&id).await {
-                        if let Some(properties) = peripheral.properties().await? {
-                            if let Some(local_name) = properties.local_name {
-                                if self.device_database.contains_device(&local_name) {
-                                    let mut known_devices = self.known_devices.write().await;
-                                    known_devices.insert(id.clone(), local_name.clone());
-                                    drop(known_devices);
-                                    if let Ok(data) = DeviceData::decode(&properties.manufacturer_data) {
-                                        debug!("Received initial data from {}: {:?}", local_name, data);
-                                        let mut device_data = self.device_data.write().await;
-                                        device_data.insert(local_name, data);
-                                    }
-                                }
-                            }
-                        }
-                    }
+    async fn process_advertisement(&self, advertisement: Advertisement) {
+        trace!("Received advertisement {:?}", advertisement);
+        if let Some(local_name) = &advertisement.local_name {
+            let is_known = self.known_devices.get(&advertisement.address).as_ref() == Some(local_name);
+            if !is_known && self.device_database.contains_device(local_name) {
+                let address = advertisement.address.clone();
+                if self.known_devices.update(|known| known.insert(address.clone(), local_name.clone())).is_none() {
+                    debug!("Discovered device {} at {}", local_name, advertisement.address);
                 }
-                CentralEvent::ManufacturerDataAdvertisement {
-                    id,
-                    manufacturer_data,
-                } => {
-                    let known_devices = self.known_devices.read().await;
-                    if let Some(local_name) = known_devices.get(&id) {
-                        if let Ok(data) = DeviceData::decode(&manufacturer_data) {
-                            debug!("Received data from {}: {:?}", local_name, data);
-                            let mut device_data = self.device_data.write().await;
-                            device_data.insert(local_name.clone(), data);
-                        }
-                    }
+            }
+        }
+        // devices can be removed from the database at runtime
+        let local_name = self.known_devices.get(&advertisement.address)
+            .filter(|local_name| self.device_database.contains_device(local_name));
+        if let Some(local_name) = local_name {
+            self.liveness.mark_seen(&local_name);
+            let decoded = DeviceData::decode(&advertisement.manufacturer_data);
+            if !advertisement.discovered {
+                // released before publishing, so that diagnostics never hold up the readings
+                let mut link_statistics = self.link_statistics.write().await;
+                let link_statistics = link_statistics.entry(local_name.clone())
+                    .or_insert_with(|| LinkStatistics::new(ADVERTISEMENT_INTERVAL, Instant::now()));
+                link_statistics.record_advertisement(Instant::now(), advertisement.rssi);
+                if matches!(decoded, Err(DeviceDataError::InvalidData)) {
+                    link_statistics.record_decode_failure();
+                }
+            }
+            match decoded {
+                Ok(data) => {
+                    debug!("Received data from {}: {:?}", local_name, data);
+                    self.publish(local_name.clone(), ReadingData::Measured(data)).await;
+                    self.update_virtual_devices(&local_name).await;
                 }
-                _ => {}
+                Err(DeviceDataError::InvalidData) => {
+                    warn!("Unable to decode data from {}: {:?}", local_name, advertisement.manufacturer_data);
+                }
+                Err(DeviceDataError::UnsupportedDevice) => {}
             }
         }
-        Ok(())
     }
 
-    pub async fn get_latest_device_data(&self, local_name: &String) -> Option<DeviceData> {
-        let device_data = self.device_data.read().await;
-        match device_data.get(local_name) {
-            Some(device_data) => Some(device_data.clone()),
-            None => None,
+    async fn publish(&self, local_name: String, data: ReadingData) {
+        let recent_reading = RecentReading {
+            timestamp: data.last_update_timestamp(),
+            temperature_in_c: data.temperature_in_c(),
+            humidity: data.humidity(),
+        };
+        self.recent_readings.lock().expect("Could not lock mutex").push(&local_name, recent_reading);
+        let reading = self.replay_buffer.lock().expect("Could not lock mutex").push(local_name.clone(), data);
+        self.device_data.update(|device_data| device_data.insert(local_name.clone(), reading.clone()));
+        // nobody might be listening, which is fine
+        let _ = self.readings.send(reading);
+    }
+
+    /// Recomputes the virtual devices that depend on the device that just received a reading.
+    async fn update_virtual_devices(&self, input: &str) {
+        let devices = self.device_database.get_all_devices();
+        let readings = self.device_data.snapshot();
+        let value = |local_name: &str, quantity: Quantity| {
+            // the last values of devices that went offline are too old to be used
+            if !self.get_liveness(local_name).online {
+                return None;
+            }
+            let data = readings.get(local_name)?.data;
+            match quantity {
+                Quantity::Temperature => data.temperature_in_c(),
+                Quantity::Humidity => data.humidity(),
+                Quantity::Battery => data.battery().map(f32::from),
+            }
+        };
+        let dependents = devices.iter().filter(|(_, device)| device.is_virtual() && device.inputs().contains(&input));
+        for (local_name, device) in dependents {
+            let temperature = device.temperature.as_ref().and_then(|expression| expression.evaluate(&value));
+            let humidity = device.humidity.as_ref().and_then(|expression| expression.evaluate(&value));
+            if temperature.is_none() && humidity.is_none() {
+                continue;
+            }
+            let data = ReadingData::Derived(DerivedData::new(temperature, humidity));
+            trace!("Computed data of {}: {:?}", local_name, data);
+            self.liveness.mark_seen(local_name);
+            self.publish(local_name.clone(), data).await;
         }
     }
-}
\ No newline at end of file
+
+    pub fn get_latest_reading(&self, local_name: &str) -> Option<Reading> {
+        self.device_data.get(local_name)
+    }
+
+    /// Returns a consistent snapshot of the latest readings of all devices, keyed by their local names.
+    pub fn get_latest_readings(&self) -> Arc<HashMap<String, Reading>> {
+        self.device_data.snapshot()
+    }
+
+    /// How far back the recent readings go.
+    pub fn recent_readings_window(&self) -> Duration {
+        self.recent_readings.lock().expect("Could not lock mutex").window()
+    }
+
+    /// Returns the readings of the device within the given time, oldest first.
+    pub fn get_recent_readings(&self, local_name: &str, window: Duration) -> Vec<RecentReading> {
+        let since = SystemTime::now().checked_sub(window).unwrap_or(SystemTime::UNIX_EPOCH);
+        self.recent_readings.lock().expect("Could not lock mutex").since(local_name, since)
+    }
+
+    /// Returns the lowest and highest values of the device within the given time.
+    pub fn get_extremes(&self, local_name: &str, window: Duration) -> Extremes {
+        let since = SystemTime::now().checked_sub(window).unwrap_or(SystemTime::UNIX_EPOCH);
+        let readings = self.recent_readings.lock().expect("Could not lock mutex").since(local_name, since);
+        // computed after the lock is released, so that the readings can keep coming in meanwhile
+        Extremes::of(&readings)
+    }
+
+    /// Returns the devices and the latest readings the collector knows of, to be restored after a restart.
+    pub fn get_saved_state(&self) -> SavedState {
+        let known_devices = HashMap::clone(&self.known_devices.snapshot());
+        let readings = self.device_data.snapshot().iter()
+            .map(|(local_name, reading)| (local_name.clone(), SavedReading::of(&reading.data)))
+            .collect();
+        SavedState::new(known_devices, readings)
+    }
+
+    /// Takes over the devices and readings saved before the service was restarted.
+    ///
+    /// Devices that are no longer configured are left out, and so is anything received since the start.
+    /// Restored readings aren't published, as they were already when they were received.
+    pub fn restore(&self, state: SavedState) {
+        let is_configured = |local_name: &String| self.device_database.contains_device(local_name);
+        self.known_devices.update(|known_devices| {
+            for (address, local_name) in state.known_devices.iter().filter(|(_, local_name)| is_configured(local_name)) {
+                known_devices.entry(address.clone()).or_insert_with(|| local_name.clone());
+            }
+        });
+        let restored = self.device_data.update(|device_data| {
+            let mut restored = 0;
+            for (local_name, saved) in state.readings.iter().filter(|(local_name, _)| is_configured(local_name)) {
+                if !device_data.contains_key(local_name) {
+                    let reading = Reading { sequence_number: 0, local_name: local_name.clone(), data: saved.data(), restored: true };
+                    device_data.insert(local_name.clone(), reading);
+                    restored += 1;
+                }
+            }
+            restored
+        });
+        info!("Restored the latest readings of {} devices", restored);
+    }
+
+    /// Writes the state to the given file on a blocking thread, as it's synced to the disk, logging any failure.
+    pub async fn save_state(&self, path: &Path) {
+        let state = self.get_saved_state();
+        let target = path.to_path_buf();
+        let result = spawn_blocking(move || state.write(&target)).await.expect("Saving the state panicked");
+        match result {
+            Ok(()) => trace!("Saved the state to {:?}", path),
+            Err(err) => error!("Unable to save the state to {:?}: {}", path, err),
+        }
+    }
+
+    /// Saves the state every once in a while, so that little is lost if the service isn't stopped cleanly.
+    pub async fn save_state_periodically(&self, path: &Path) {
+        loop {
+            sleep(STATE_SAVE_INTERVAL).await;
+            self.save_state(path).await;
+        }
+    }
+
+    /// Returns the address the device was last discovered at.
+    pub fn get_address(&self, local_name: &str) -> Option<String> {
+        self.known_devices.snapshot().iter()
+            .find(|(_, known_name)| known_name.as_str() == local_name)
+            .map(|(address, _)| address.clone())
+    }
+
+    /// Returns the readings that came after the given sequence number, if they are still available.
+    pub fn get_readings_since(&self, sequence_number: u64) -> Option<Vec<Reading>> {
+        self.replay_buffer.lock().expect("Could not lock mutex").since(sequence_number)
+    }
+
+    /// Subscribes to every reading the collector receives from now on.
+    pub fn subscribe_readings(&self, subscriber: &str) -> ReadingSubscriber {
+        ReadingSubscriber::new(subscriber.to_string(), self.readings.subscribe())
+    }
+
+    pub async fn get_link_summary(&self, local_name: &str) -> Option<LinkSummary> {
+        let link_statistics = self.link_statistics.read().await;
+        link_statistics.get(local_name).map(|statistics| statistics.summarize(Instant::now()))
+    }
+
+    pub fn get_liveness(&self, local_name: &str) -> Liveness {
+        self.liveness.get(local_name, self.stale_after(local_name))
+    }
+
+    pub fn subscribe_liveness_changes(&self) -> broadcast::Receiver<LivenessChange> {
+        self.liveness.subscribe()
+    }
+
+    fn stale_after(&self, local_name: &str) -> Duration {
+        self.device_database.get_stale_after(local_name).unwrap_or(self.stale_after)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collector() -> Collector {
+        let mut device_database = DeviceDatabase::default();
+        device_database.add_device("GVH5075_6A19".to_string(), "Living Room".to_string());
+        Collector::new(Arc::new(device_database), Duration::from_secs(60), Duration::from_secs(60 * 60))
+    }
+
+    fn advertisement(temperature_in_c: f32, discovered: bool) -> Advertisement {
+        Advertisement {
+            address: "A4:C1:38:00:6A:19".to_string(),
+            local_name: Some("GVH5075_6A19".to_string()),
+            rssi: Some(-67),
+            manufacturer_data: DeviceData::encode(temperature_in_c, 40.0, 100),
+            discovered,
+        }
+    }
+
+    /// Fails to start the given number of times, and then has no advertisements.
+    struct FlakySource {
+        failures: Mutex<usize>,
+    }
+
+    #[tonic::async_trait]
+    impl AdvertisementSource for FlakySource {
+        async fn advertisements(&self) -> Result<AdvertisementStream, Box<dyn Error>> {
+            let mut failures = self.failures.lock().expect("Could not lock mutex");
+            match *failures {
+                0 => Ok(Box::pin(futures::stream::empty())),
+                _ => {
+                    *failures -= 1;
+                    Err("adapter is not powered on".into())
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_scanning_is_retried_until_the_deadline() {
+        tokio::time::pause();
+        let collector = collector();
+        let start = tokio::time::Instant::now();
+        let source = FlakySource { failures: Mutex::new(2) };
+        collector.start_retrying(&source, start + Duration::from_secs(5)).await.unwrap();
+        assert_eq!(start.elapsed().as_secs(), 2);
+        assert_eq!(collector.get_state(), CollectorState::Stopped);
+
+        let start = tokio::time::Instant::now();
+        let source = FlakySource { failures: Mutex::new(usize::MAX) };
+        assert!(collector.start_retrying(&source, start + Duration::from_secs(5)).await.is_err());
+        assert_eq!(start.elapsed().as_secs(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_discoveries_are_not_counted_as_advertisements() {
+        let collector = collector();
+        collector.process_advertisement(advertisement(21.0, true)).await;
+        collector.process_advertisement(advertisement(21.5, false)).await;
+        let summary = collector.get_link_summary("GVH5075_6A19").await.unwrap();
+        assert_eq!(summary.advertisements_received, 1);
+        // the reading is taken either way
+        assert!(collector.get_latest_reading("GVH5075_6A19").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_readings_are_served_as_measured() {
+        let device_database = DeviceDatabase::parse(r#"
+            [GVH5075_6A19]
+            friendly_name = "Living Room"
+            calibration = { temperature_offset_in_c = -0.5, humidity_offset = 1.5 }
+        "#);
+        let collector = Collector::new(Arc::new(device_database), Duration::from_secs(60), Duration::from_secs(60 * 60));
+        let mut readings = collector.subscribe_readings("test");
+        collector.process_advertisement(advertisement(21.0, false)).await;
+        // the calibration is only reported, for the clients to apply
+        let published = readings.recv().await.unwrap();
+        assert_eq!((published.data.temperature_in_c(), published.data.humidity()), (Some(21.0), Some(40.0)));
+        let recent = collector.get_recent_readings("GVH5075_6A19", Duration::from_secs(60));
+        assert_eq!(recent.iter().map(|reading| reading.temperature_in_c).collect::<Vec<_>>(), vec![Some(21.0)]);
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use btleplug::api::{BDAddr, Central, CentralEvent, Manager as _, Peripheral, ScanFilter};
+use btleplug::platform::{Adapter, Manager, PeripheralId};
+use futures::stream::StreamExt;
+
+use crate::retry::{retry, Backoff};
+
+use super::{Advertisement, AdvertisementSource, AdvertisementStream, CollectorError};
+
+/// How long to wait before looking for an adapter again.
+const ADAPTER_BACKOFF: Backoff = Backoff { initial: Duration::from_millis(500), max: Duration::from_secs(10) };
+
+/// How long the properties of a peripheral are cached for, before they are read again (over D-Bus, on Linux)
+/// to keep its signal strength up to date.
+const PROPERTIES_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Scans for advertisements using the first available bluetooth adapter.
+pub struct BluetoothSource {
+    central: Adapter,
+}
+
+impl BluetoothSource {
+    pub async fn new() -> Result<BluetoothSource, Box<dyn Error>> {
+        let manager = Manager::new().await?;
+
+        // get the first bluetooth adapter
+        let adapters = manager.adapters().await?;
+        match adapters.into_iter().next() {
+            Some(central) => Ok(BluetoothSource { central }),
+            None => Err(Box::new(CollectorError::NoAdaptersFound)),
+        }
+    }
+
+    /// Keeps looking for an adapter until there is one, or until the timeout passes.
+    ///
+    /// At boot, the adapter often shows up a few seconds after the service starts.
+    pub async fn wait_for_adapter(timeout: Duration) -> Result<BluetoothSource, Box<dyn Error>> {
+        wait_for(timeout, BluetoothSource::new).await
+    }
+}
+
+/// Separate from looking for the adapter itself, so that the waiting can be tested without one.
+async fn wait_for<T, F, Fut>(timeout: Duration, find: F) -> Result<T, Box<dyn Error>>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Box<dyn Error>>>,
+{
+    retry(ADAPTER_BACKOFF, tokio::time::Instant::now() + timeout, "Bluetooth adapter is not ready", find).await
+}
+
+#[tonic::async_trait]
+impl AdvertisementSource for BluetoothSource {
+    async fn advertisements(&self) -> Result<AdvertisementStream, Box<dyn Error>> {
+        let events = self.central.events().await?;
+        self.central.start_scan(ScanFilter::default()).await?;
+
+        let central = self.central.clone();
+        let peripherals = Arc::new(Mutex::new(HashMap::new()));
+        Ok(Box::pin(events.filter_map(move |event| {
+            let (central, peripherals) = (central.clone(), Arc::clone(&peripherals));
+            async move { to_advertisement(&central, &peripherals, event).await }
+        })))
+    }
+}
+
+/// What is known about a peripheral besides its advertisements.
+#[derive(Clone)]
+struct CachedPeripheral {
+    address: String,
+    local_name: Option<String>,
+    rssi: Option<i16>,
+    read_at: Instant,
+}
+
+async fn to_advertisement(
+    central: &Adapter,
+    peripherals: &Mutex<HashMap<PeripheralId, CachedPeripheral>>,
+    event: CentralEvent,
+) -> Option<Advertisement> {
+    trace!("Received event {:?}", event);
+    match event {
+        CentralEvent::DeviceDiscovered(id) => {
+            let (peripheral, manufacturer_data) = read_properties(central, &id).await?;
+            peripherals.lock().expect("Could not lock mutex").insert(id, peripheral.clone());
+            // the manufacturer data of the peripheral, if any, is taken from its properties
+            Some(advertisement(peripheral, manufacturer_data, true))
+        }
+        CentralEvent::ManufacturerDataAdvertisement { id, manufacturer_data } => {
+            let cached = peripherals.lock().expect("Could not lock mutex").get(&id).cloned()
+                .filter(|peripheral| peripheral.read_at.elapsed() < PROPERTIES_REFRESH_INTERVAL);
+            let peripheral = match cached {
+                Some(peripheral) => peripheral,
+                // advertising since before the scan started, or due for a refresh
+                None => {
+                    let (peripheral, _) = read_properties(central, &id).await?;
+                    peripherals.lock().expect("Could not lock mutex").insert(id, peripheral.clone());
+                    peripheral
+                }
+            };
+            Some(advertisement(peripheral, manufacturer_data, false))
+        }
+        _ => None,
+    }
+}
+
+async fn read_properties(central: &Adapter, id: &PeripheralId) -> Option<(CachedPeripheral, HashMap<u16, Vec<u8>>)> {
+    let peripheral = central.peripheral(id).await.ok()?;
+    let properties = match peripheral.properties().await {
+        Ok(properties) => properties?,
+        Err(err) => {
+            warn!("Unable to read properties of {:?}: {:?}", id, err);
+            return None;
+        }
+    };
+    let peripheral = CachedPeripheral {
+        address: peripheral_address(id, properties.address),
+        local_name: properties.local_name,
+        rssi: properties.rssi,
+        read_at: Instant::now(),
+    };
+    Some((peripheral, properties.manufacturer_data))
+}
+
+fn advertisement(peripheral: CachedPeripheral, manufacturer_data: HashMap<u16, Vec<u8>>, discovered: bool) -> Advertisement {
+    Advertisement {
+        address: peripheral.address,
+        local_name: peripheral.local_name,
+        rssi: peripheral.rssi,
+        manufacturer_data,
+        discovered,
+    }
+}
+
+fn peripheral_address(id: &PeripheralId, address: BDAddr) -> String {
+    // some platforms (e.g. macOS) do not expose MAC addresses
+    if address == BDAddr::default() {
+        format!("{:?}", id)
+    } else {
+        address.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_adapter_is_looked_for_until_the_timeout() {
+        tokio::time::pause();
+        let start = tokio::time::Instant::now();
+        let attempts = RefCell::new(vec![]);
+        let find = || {
+            // in tenths of a second, as every sleep takes a millisecond longer
+            attempts.borrow_mut().push(start.elapsed().as_millis() / 100);
+            async { Err::<(), Box<dyn Error>>(Box::new(CollectorError::NoAdaptersFound)) }
+        };
+        assert!(wait_for(Duration::from_secs(30), find).await.is_err());
+        assert_eq!(attempts.into_inner(), vec![0, 5, 15, 35, 75, 155, 255, 300]);
+
+        let start = tokio::time::Instant::now();
+        let attempts = RefCell::new(0);
+        let find = || {
+            *attempts.borrow_mut() += 1;
+            let found = *attempts.borrow() == 3;
+            async move { if found { Ok(()) } else { Err("not powered on".into()) } }
+        };
+        wait_for(Duration::from_secs(30), find).await.unwrap();
+        assert_eq!(start.elapsed().as_millis() / 100, 15);
+    }
+}
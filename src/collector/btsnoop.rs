@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+use super::Advertisement;
+use super::capture::CaptureRecord;
+
+const BTSNOOP_MAGIC: &[u8] = b"btsnoop\0";
+const BTSNOOP_VERSION: u32 = 1;
+const HEADER_LEN: usize = 16;
+const RECORD_HEADER_LEN: usize = 24;
+
+// datalink types
+const DATALINK_HCI_UNENCAPSULATED: u32 = 1001;
+const DATALINK_HCI_UART: u32 = 1002;
+const DATALINK_LINUX_MONITOR: u32 = 2001;
+
+const H4_EVENT_PACKET: u8 = 0x04;
+const MONITOR_EVENT_PACKET: u32 = 3;
+
+const LE_META_EVENT: u8 = 0x3E;
+const LE_ADVERTISING_REPORT: u8 = 0x02;
+const LE_EXTENDED_ADVERTISING_REPORT: u8 = 0x0D;
+
+const AD_SHORTENED_LOCAL_NAME: u8 = 0x08;
+const AD_COMPLETE_LOCAL_NAME: u8 = 0x09;
+const AD_MANUFACTURER_SPECIFIC_DATA: u8 = 0xFF;
+
+#[derive(Debug, thiserror::Error, Eq, PartialEq)]
+pub enum BtsnoopError {
+    #[error("not a btsnoop file")]
+    NotBtsnoop,
+    #[error("unsupported btsnoop version {0}")]
+    UnsupportedVersion(u32),
+    #[error("unsupported btsnoop datalink type {0}")]
+    UnsupportedDatalink(u32),
+}
+
+pub fn is_btsnoop(bytes: &[u8]) -> bool {
+    bytes.starts_with(BTSNOOP_MAGIC)
+}
+
+/// Extracts LE advertising reports from a btsnoop file (as written by `btmon -w` or Android's HCI snoop log).
+pub fn parse_btsnoop(bytes: &[u8]) -> Result<Vec<CaptureRecord>, BtsnoopError> {
+    if !is_btsnoop(bytes) || bytes.len() < HEADER_LEN {
+        return Err(BtsnoopError::NotBtsnoop);
+    }
+    let version = read_u32(&bytes[8..]);
+    if version != BTSNOOP_VERSION {
+        return Err(BtsnoopError::UnsupportedVersion(version));
+    }
+    let datalink = read_u32(&bytes[12..]);
+    if ![DATALINK_HCI_UNENCAPSULATED, DATALINK_HCI_UART, DATALINK_LINUX_MONITOR].contains(&datalink) {
+        return Err(BtsnoopError::UnsupportedDatalink(datalink));
+    }
+
+    let mut records = vec![];
+    let mut first_timestamp = None;
+    let mut rest = &bytes[HEADER_LEN..];
+    while !rest.is_empty() {
+        if rest.len() < RECORD_HEADER_LEN {
+            warn!("Ignoring truncated btsnoop record header");
+            break;
+        }
+        let included_length = read_u32(&rest[4..]) as usize;
+        let flags = read_u32(&rest[8..]);
+        let timestamp = i64::from_be_bytes(rest[16..24].try_into().unwrap());
+        rest = &rest[RECORD_HEADER_LEN..];
+        if rest.len() < included_length {
+            warn!("Ignoring truncated btsnoop record");
+            break;
+        }
+        let (packet, remainder) = rest.split_at(included_length);
+        rest = remainder;
+
+        let event = match datalink {
+            DATALINK_HCI_UNENCAPSULATED if flags & 0x03 == 0x03 => packet,
+            DATALINK_HCI_UART if packet.first() == Some(&H4_EVENT_PACKET) => &packet[1..],
+            DATALINK_LINUX_MONITOR if flags & 0xFFFF == MONITOR_EVENT_PACKET => packet,
+            _ => continue,
+        };
+        let advertisements = parse_le_advertising_reports(event);
+        if advertisements.is_empty() {
+            continue;
+        }
+        // timestamps are in microseconds, only the time relative to the first report matters
+        let first_timestamp = *first_timestamp.get_or_insert(timestamp);
+        let elapsed_ms = (timestamp.saturating_sub(first_timestamp) / 1000).max(0) as u64;
+        records.extend(advertisements.into_iter().map(|advertisement| CaptureRecord { elapsed_ms, advertisement }));
+    }
+    Ok(records)
+}
+
+fn parse_le_advertising_reports(event: &[u8]) -> Vec<Advertisement> {
+    let mut advertisements = vec![];
+    // event code, parameter length, subevent code, number of reports
+    if event.len() < 4 || event[0] != LE_META_EVENT {
+        return advertisements;
+    }
+    let subevent = event[2];
+    let num_reports = event[3];
+    let mut rest = &event[4..];
+    for _ in 0..num_reports {
+        let parsed = match subevent {
+            LE_ADVERTISING_REPORT => parse_advertising_report(rest),
+            LE_EXTENDED_ADVERTISING_REPORT => parse_extended_advertising_report(rest),
+            _ => None,
+        };
+        match parsed {
+            Some((advertisement, remainder)) => {
+                advertisements.push(advertisement);
+                rest = remainder;
+            }
+            None => break,
+        }
+    }
+    advertisements
+}
+
+fn parse_advertising_report(report: &[u8]) -> Option<(Advertisement, &[u8])> {
+    // event type, address type, address, data length
+    let header = report.get(..9)?;
+    let data_length = header[8] as usize;
+    let data = report.get(9..9 + data_length)?;
+    let rssi = *report.get(9 + data_length)? as i8;
+    let advertisement = to_advertisement(&header[2..8], data, rssi);
+    Some((advertisement, &report[10 + data_length..]))
+}
+
+fn parse_extended_advertising_report(report: &[u8]) -> Option<(Advertisement, &[u8])> {
+    // event type (2), address type, address (6), primary PHY, secondary PHY, SID, TX power, RSSI,
+    // periodic advertising interval (2), direct address type, direct address (6), data length
+    let header = report.get(..24)?;
+    let rssi = header[13] as i8;
+    let data_length = header[23] as usize;
+    let data = report.get(24..24 + data_length)?;
+    let advertisement = to_advertisement(&header[3..9], data, rssi);
+    Some((advertisement, &report[24 + data_length..]))
+}
+
+fn to_advertisement(address: &[u8], data: &[u8], rssi: i8) -> Advertisement {
+    let mut local_name = None;
+    let mut manufacturer_data = HashMap::new();
+    for (ad_type, ad_data) in AdStructures(data) {
+        match ad_type {
+            AD_SHORTENED_LOCAL_NAME | AD_COMPLETE_LOCAL_NAME => {
+                local_name = Some(String::from_utf8_lossy(ad_data).into_owned());
+            }
+            AD_MANUFACTURER_SPECIFIC_DATA if ad_data.len() >= 2 => {
+                let company_id = u16::from_le_bytes([ad_data[0], ad_data[1]]);
+                manufacturer_data.insert(company_id, ad_data[2..].to_vec());
+            }
+            _ => {}
+        }
+    }
+    Advertisement {
+        // addresses are transmitted in little-endian order
+        address: address.iter().rev().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(":"),
+        local_name,
+        rssi: Some(rssi as i16),
+        manufacturer_data,
+        discovered: false,
+    }
+}
+
+/// Iterates over the (type, data) pairs of advertising data.
+struct AdStructures<'a>(&'a [u8]);
+
+impl<'a> Iterator for AdStructures<'a> {
+    type Item = (u8, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let length = *self.0.first()? as usize;
+        if length == 0 || self.0.len() < length + 1 {
+            return None;
+        }
+        let ad_type = self.0[1];
+        let ad_data = &self.0[2..length + 1];
+        self.0 = &self.0[length + 1..];
+        Some((ad_type, ad_data))
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes[..4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // LE Advertising Report from GVH5075_6A19 (A4:C1:38:00:6A:19), RSSI -67
+    const ADVERTISING_REPORT: &[u8] = &[
+        0x3E, 0x28, 0x02, 0x01, 0x00, 0x00, 0x19, 0x6A, 0x00, 0x38, 0xC1, 0xA4, 0x1C,
+        0x0D, 0x09, b'G', b'V', b'H', b'5', b'0', b'7', b'5', b'_', b'6', b'A', b'1', b'9',
+        0x09, 0xFF, 0x88, 0xEC, 0x00, 0x03, 0x84, 0x7A, 0x39, 0x00,
+        0x03, 0x03, 0x88, 0xEC,
+        0xBD,
+    ];
+
+    fn btsnoop(datalink: u32, packets: &[(u32, u64, &[u8])]) -> Vec<u8> {
+        let mut bytes = BTSNOOP_MAGIC.to_vec();
+        bytes.extend_from_slice(&BTSNOOP_VERSION.to_be_bytes());
+        bytes.extend_from_slice(&datalink.to_be_bytes());
+        for (flags, timestamp, packet) in packets {
+            bytes.extend_from_slice(&(packet.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(&(packet.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(&flags.to_be_bytes());
+            bytes.extend_from_slice(&0u32.to_be_bytes());
+            bytes.extend_from_slice(&timestamp.to_be_bytes());
+            bytes.extend_from_slice(packet);
+        }
+        bytes
+    }
+
+    fn expected_advertisement() -> Advertisement {
+        Advertisement {
+            address: "A4:C1:38:00:6A:19".to_string(),
+            local_name: Some("GVH5075_6A19".to_string()),
+            rssi: Some(-67),
+            manufacturer_data: HashMap::from([(0xEC88, vec![0x00, 0x03, 0x84, 0x7A, 0x39, 0x00])]),
+            discovered: false,
+        }
+    }
+
+    #[test]
+    fn test_not_btsnoop_file_is_rejected() {
+        assert_eq!(parse_btsnoop(b"{\"elapsed_ms\": 0}").err(), Some(BtsnoopError::NotBtsnoop));
+    }
+
+    #[test]
+    fn test_unsupported_datalink_is_rejected() {
+        let bytes = btsnoop(1003, &[]);
+        assert_eq!(parse_btsnoop(&bytes).err(), Some(BtsnoopError::UnsupportedDatalink(1003)));
+    }
+
+    #[test]
+    fn test_monitor_advertising_reports_parse_correctly() {
+        let command: &[u8] = &[0x0C, 0x20, 0x02, 0x01, 0x00];
+        let bytes = btsnoop(DATALINK_LINUX_MONITOR, &[
+            (2, 1_000_000, command),
+            (3, 2_000_000, ADVERTISING_REPORT),
+            (3, 4_500_000, ADVERTISING_REPORT),
+        ]);
+        let actual = parse_btsnoop(&bytes).expect("parsing failed");
+        assert_eq!(actual, vec![
+            CaptureRecord { elapsed_ms: 0, advertisement: expected_advertisement() },
+            CaptureRecord { elapsed_ms: 2500, advertisement: expected_advertisement() },
+        ]);
+    }
+
+    #[test]
+    fn test_uart_advertising_reports_parse_correctly() {
+        let mut packet = vec![H4_EVENT_PACKET];
+        packet.extend_from_slice(ADVERTISING_REPORT);
+        let bytes = btsnoop(DATALINK_HCI_UART, &[(1, 0, &packet)]);
+        let actual = parse_btsnoop(&bytes).expect("parsing failed");
+        assert_eq!(actual, vec![CaptureRecord { elapsed_ms: 0, advertisement: expected_advertisement() }]);
+    }
+
+    #[test]
+    fn test_truncated_report_does_not_panic() {
+        let bytes = btsnoop(DATALINK_LINUX_MONITOR, &[(3, 0, &ADVERTISING_REPORT[..20])]);
+        assert_eq!(parse_btsnoop(&bytes), Ok(vec![]));
+    }
+}
@@ -0,0 +1,151 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use futures::stream::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use super::{Advertisement, AdvertisementSource, AdvertisementStream};
+
+/// One line of a capture file: an advertisement and the time it was received,
+/// relative to the start of the capture.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CaptureRecord {
+    pub elapsed_ms: u64,
+    pub advertisement: Advertisement,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CaptureError {
+    #[error("malformed capture record at line {line}: {source}")]
+    MalformedRecord { line: usize, source: serde_json::Error },
+}
+
+impl CaptureRecord {
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_millis(self.elapsed_ms)
+    }
+}
+
+/// Parses a capture file written by [`RecordingSource`].
+pub fn parse_capture(reader: impl BufRead) -> Result<Vec<CaptureRecord>, Box<dyn Error>> {
+    let mut records = vec![];
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = serde_json::from_str(&line)
+            .map_err(|source| CaptureError::MalformedRecord { line: index + 1, source })?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Wraps another source and writes every advertisement it produces into a capture file.
+pub struct RecordingSource {
+    inner: Box<dyn AdvertisementSource>,
+    path: PathBuf,
+}
+
+impl RecordingSource {
+    pub fn new(inner: Box<dyn AdvertisementSource>, path: PathBuf) -> RecordingSource {
+        RecordingSource { inner, path }
+    }
+}
+
+#[tonic::async_trait]
+impl AdvertisementSource for RecordingSource {
+    async fn advertisements(&self) -> Result<AdvertisementStream, Box<dyn Error>> {
+        let file = File::create(&self.path)?;
+        let advertisements = self.inner.advertisements().await?;
+        info!("Recording advertisements to {:?}", self.path);
+        // written on a thread of its own, so that the file never holds up the advertisements
+        let (records, receiver) = mpsc::channel();
+        thread::spawn(move || write_records(BufWriter::new(file), receiver));
+        let start = Instant::now();
+        Ok(Box::pin(advertisements.inspect(move |advertisement| {
+            let record = CaptureRecord {
+                elapsed_ms: start.elapsed().as_millis() as u64,
+                advertisement: advertisement.clone(),
+            };
+            // the writer only stops if the stream is gone
+            let _ = records.send(record);
+        })))
+    }
+}
+
+/// Writes the records until the stream they come from is dropped,
+/// flushing whenever it catches up, so that the file only lags behind while the records keep coming.
+fn write_records(mut writer: impl Write, receiver: mpsc::Receiver<CaptureRecord>) {
+    while let Ok(record) = receiver.recv() {
+        let result = std::iter::once(record).chain(receiver.try_iter())
+            .try_for_each(|record| write_record(&mut writer, &record))
+            .and_then(|()| Ok(writer.flush()?));
+        if let Err(err) = result {
+            error!("Unable to record advertisement: {:?}", err);
+        }
+    }
+}
+
+fn write_record(writer: &mut impl Write, record: &CaptureRecord) -> Result<(), Box<dyn Error>> {
+    writeln!(writer, "{}", serde_json::to_string(record)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn test_capture_round_trip() {
+        let record = CaptureRecord {
+            elapsed_ms: 1500,
+            advertisement: Advertisement {
+                address: "A4:C1:38:00:6A:19".to_string(),
+                local_name: Some("GVH5075_6A19".to_string()),
+                rssi: Some(-67),
+                manufacturer_data: HashMap::from([(0xEC88, vec![0x00, 0x03, 0x84, 0x7a, 0x39, 0x00])]),
+                discovered: false,
+            },
+        };
+        let line = serde_json::to_string(&record).expect("serialization failed");
+        let actual = parse_capture(format!("{}\n\n{}\n", line, line).as_bytes()).expect("parsing failed");
+        assert_eq!(actual, vec![record.clone(), record]);
+    }
+
+    #[test]
+    fn test_records_are_written_until_the_stream_is_gone() {
+        let record = |elapsed_ms| CaptureRecord {
+            elapsed_ms,
+            advertisement: Advertisement {
+                address: "A4:C1:38:00:6A:19".to_string(),
+                local_name: None,
+                rssi: None,
+                manufacturer_data: HashMap::new(),
+                discovered: true,
+            },
+        };
+        let (records, receiver) = mpsc::channel();
+        for elapsed_ms in 0..3 {
+            records.send(record(elapsed_ms)).unwrap();
+        }
+        drop(records);
+        let mut written = vec![];
+        write_records(&mut written, receiver);
+        assert_eq!(parse_capture(written.as_slice()).unwrap(), vec![record(0), record(1), record(2)]);
+    }
+
+    #[test]
+    fn test_malformed_record_reports_line() {
+        let err = parse_capture("\n{\"elapsed_ms\": 1}\n".as_bytes()).expect_err("parsing succeeded");
+        assert!(err.to_string().starts_with("malformed capture record at line 2"));
+    }
+}
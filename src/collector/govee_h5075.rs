@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 use std::convert::TryInto;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 #[derive(Debug, Copy, Clone)]
 pub struct DeviceData {
@@ -20,6 +20,9 @@ pub enum DeviceDataError {
 
 const H5075_UPDATE_UUID16: u16 = 0xEC88;
 
+/// How often H5075 broadcasts its readings.
+pub const ADVERTISEMENT_INTERVAL: Duration = Duration::from_secs(2);
+
 impl DeviceData {
     pub fn decode(manufacturer_data: &HashMap<u16, Vec<u8>>) -> Result<DeviceData, DeviceDataError> {
         let bytes: [u8; 6] = match manufacturer_data.get(&H5075_UPDATE_UUID16) {
@@ -44,10 +47,39 @@ impl DeviceData {
         Ok(DeviceData { temperature, humidity, battery, last_update_timestamp })
     }
 
+    /// A reading received before the service was restarted.
+    pub fn restored(temperature_in_c: f32, humidity: f32, battery: u8, last_update_timestamp: SystemTime) -> DeviceData {
+        DeviceData {
+            temperature: (temperature_in_c * 10.0).round() as i32,
+            humidity: (humidity * 10.0).round() as u16,
+            battery,
+            last_update_timestamp,
+        }
+    }
+
+    /// Produces manufacturer data that [`DeviceData::decode`] would decode into the given values.
+    pub fn encode(temperature_in_c: f32, humidity: f32, battery: u8) -> HashMap<u16, Vec<u8>> {
+        let temperature = (temperature_in_c * 10.0).round() as i32;
+        let humidity = (humidity * 10.0).round().clamp(0.0, 999.0) as u32;
+        let raw_data = temperature.unsigned_abs() * 1000 + humidity;
+        let temp_sign = if temperature < 0 { 0x80 } else { 0x00 };
+        let bytes = vec![
+            0x00,
+            ((raw_data >> 16) & 0x7f) as u8 | temp_sign,
+            (raw_data >> 8) as u8,
+            raw_data as u8,
+            battery,
+            0x00,
+        ];
+        HashMap::from([(H5075_UPDATE_UUID16, bytes)])
+    }
+
     pub fn temperature_in_c(&self) -> f32 {
         self.temperature as f32 / 10.0
     }
 
+    // nothing serves Fahrenheit yet, only the decoding tests check it
+    #[cfg(test)]
     pub fn temperature_in_f(&self) -> f32 {
         self.temperature as f32 * 0.18 + 32.0
     }
@@ -130,6 +162,16 @@ mod tests {
         assert_eq!(actual.battery(), 100);
     }
 
+    #[test]
+    fn test_encoded_data_parses_correctly() {
+        let data = DeviceData::encode(-12.3, 45.6, 78);
+        let actual = DeviceData::decode(&data).expect("decode failed");
+        assert_eq!(actual.temperature_in_c(), -12.3);
+        assert_eq!(actual.humidity(), 45.6);
+        assert_eq!(actual.battery(), 78);
+        assert_eq!(DeviceData::encode(-4.8, 53.8, 100)[&H5075_UPDATE_UUID16], vec![0x00, 0x80, 0xBD, 0x9A, 0x64, 0x00]);
+    }
+
     #[test]
     fn test_sample_data_below_zero_c_parses_correctly() {
         let data = HashMap::from([
@@ -0,0 +1,175 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Sliding windows the link quality is summarized over.
+pub const WINDOWS: [Duration; 3] = [
+    Duration::from_secs(60),
+    Duration::from_secs(15 * 60),
+    Duration::from_secs(60 * 60),
+];
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Distribution<T> {
+    pub min: T,
+    pub mean: f32,
+    pub max: T,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WindowStatistics {
+    pub window: Duration,
+    pub advertisements_received: usize,
+    pub intervals: Option<Distribution<Duration>>,
+    pub interval_p90: Option<Duration>,
+    /// Assumes every advertisement is received as such, which BlueZ doesn't do:
+    /// it only reports the manufacturer data when it changes, so the loss is overestimated on Linux.
+    pub estimated_packet_loss: Option<f32>,
+    pub rssi: Option<Distribution<i16>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkSummary {
+    pub advertisements_received: u64,
+    pub decode_failures: u64,
+    pub expected_interval: Duration,
+    pub windows: Vec<WindowStatistics>,
+}
+
+struct Sample {
+    received: Instant,
+    interval: Option<Duration>,
+    rssi: Option<i16>,
+}
+
+/// Per-device counters of received advertisements and their signal strength.
+pub struct LinkStatistics {
+    expected_interval: Duration,
+    first_seen: Instant,
+    advertisements_received: u64,
+    decode_failures: u64,
+    samples: VecDeque<Sample>,
+}
+
+impl LinkStatistics {
+    pub fn new(expected_interval: Duration, now: Instant) -> LinkStatistics {
+        LinkStatistics {
+            expected_interval,
+            first_seen: now,
+            advertisements_received: 0,
+            decode_failures: 0,
+            samples: VecDeque::new(),
+        }
+    }
+
+    pub fn record_advertisement(&mut self, now: Instant, rssi: Option<i16>) {
+        let interval = self.samples.back().map(|sample| now.saturating_duration_since(sample.received));
+        self.advertisements_received += 1;
+        self.samples.push_back(Sample { received: now, interval, rssi });
+        let longest_window = WINDOWS[WINDOWS.len() - 1];
+        while let Some(sample) = self.samples.front() {
+            if now.saturating_duration_since(sample.received) <= longest_window {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+
+    pub fn record_decode_failure(&mut self) {
+        self.decode_failures += 1;
+    }
+
+    pub fn summarize(&self, now: Instant) -> LinkSummary {
+        LinkSummary {
+            advertisements_received: self.advertisements_received,
+            decode_failures: self.decode_failures,
+            expected_interval: self.expected_interval,
+            windows: WINDOWS.iter().map(|window| self.summarize_window(now, *window)).collect(),
+        }
+    }
+
+    fn summarize_window(&self, now: Instant, window: Duration) -> WindowStatistics {
+        let samples: Vec<&Sample> = self.samples.iter()
+            .filter(|sample| now.saturating_duration_since(sample.received) <= window)
+            .collect();
+
+        // the first interval may have started before the window did
+        let mut intervals: Vec<Duration> = samples.iter().skip(1).filter_map(|sample| sample.interval).collect();
+        intervals.sort();
+        let interval_p90 = intervals.get(intervals.len() * 9 / 10).copied();
+        let intervals = distribution(&intervals, |interval| interval.as_secs_f32());
+
+        let rssi: Vec<i16> = samples.iter().filter_map(|sample| sample.rssi).collect();
+        let rssi = distribution(&rssi, |rssi| rssi as f32);
+
+        // only count the time the device has actually been observed for
+        let observed = now.saturating_duration_since(self.first_seen).min(window);
+        let expected = observed.as_secs_f32() / self.expected_interval.as_secs_f32();
+        let estimated_packet_loss = if expected >= 1.0 {
+            Some((1.0 - samples.len() as f32 / expected).max(0.0))
+        } else {
+            None
+        };
+
+        WindowStatistics {
+            window,
+            advertisements_received: samples.len(),
+            intervals,
+            interval_p90,
+            estimated_packet_loss,
+            rssi,
+        }
+    }
+}
+
+fn distribution<T: Copy + Ord>(values: &[T], to_f32: impl Fn(T) -> f32) -> Option<Distribution<T>> {
+    let min = *values.iter().min()?;
+    let max = *values.iter().max()?;
+    let mean = values.iter().map(|value| to_f32(*value)).sum::<f32>() / values.len() as f32;
+    Some(Distribution { min, mean, max })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_empty_statistics() {
+        let now = Instant::now();
+        let statistics = LinkStatistics::new(Duration::from_secs(2), now);
+        let summary = statistics.summarize(now);
+        assert_eq!(summary.advertisements_received, 0);
+        assert_eq!(summary.windows.len(), WINDOWS.len());
+        assert_eq!(summary.windows[0].intervals, None);
+        assert_eq!(summary.windows[0].rssi, None);
+        assert_eq!(summary.windows[0].estimated_packet_loss, None);
+    }
+
+    #[test]
+    fn test_statistics_over_windows() {
+        let start = Instant::now();
+        let mut statistics = LinkStatistics::new(Duration::from_secs(2), start);
+        // an advertisement every 4 seconds for 20 minutes, i.e. half of them lost
+        for i in 0..300 {
+            statistics.record_advertisement(start + Duration::from_secs(i * 4), Some(-60 - (i % 3) as i16));
+        }
+        statistics.record_decode_failure();
+        let now = start + Duration::from_secs(1200);
+        let summary = statistics.summarize(now);
+        assert_eq!(summary.advertisements_received, 300);
+        assert_eq!(summary.decode_failures, 1);
+
+        let minute = &summary.windows[0];
+        assert_eq!(minute.advertisements_received, 15);
+        assert_eq!(minute.intervals, Some(Distribution {
+            min: Duration::from_secs(4),
+            mean: 4.0,
+            max: Duration::from_secs(4),
+        }));
+        assert_eq!(minute.estimated_packet_loss, Some(0.5));
+        assert_eq!(minute.rssi.map(|rssi| (rssi.min, rssi.max)), Some((-62, -60)));
+
+        let hour = &summary.windows[2];
+        assert_eq!(hour.advertisements_received, 300);
+        assert_eq!(hour.estimated_packet_loss, Some(0.5));
+    }
+}
@@ -0,0 +1,158 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::sync::broadcast;
+
+use crate::snapshot::SnapshotMap;
+
+const LIVENESS_CHANGES_CAPACITY: usize = 64;
+
+/// Whether a device is currently advertising, and when it was last heard from.
+#[derive(Debug, Copy, Clone)]
+pub struct Liveness {
+    pub online: bool,
+    pub last_seen: Option<SystemTime>,
+}
+
+/// Emitted whenever a device goes online or offline.
+#[derive(Debug, Clone)]
+pub struct LivenessChange {
+    pub local_name: String,
+    pub online: bool,
+    pub last_seen: SystemTime,
+    pub timestamp: SystemTime,
+}
+
+/// Updated in place on every advertisement, so that the map only has to be copied when a device first shows up.
+struct DeviceLiveness {
+    /// In milliseconds since the epoch.
+    last_seen_ms: AtomicU64,
+    online: AtomicBool,
+}
+
+impl DeviceLiveness {
+    fn last_seen(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.last_seen_ms.load(Ordering::Relaxed))
+    }
+}
+
+/// Keeps track of devices that stop advertising for longer than their staleness timeout.
+pub struct LivenessTracker {
+    devices: SnapshotMap<String, Arc<DeviceLiveness>>,
+    changes: broadcast::Sender<LivenessChange>,
+}
+
+impl LivenessTracker {
+    pub fn new() -> LivenessTracker {
+        let (changes, _) = broadcast::channel(LIVENESS_CHANGES_CAPACITY);
+        LivenessTracker { devices: SnapshotMap::new(), changes }
+    }
+
+    pub fn mark_seen(&self, local_name: &str) {
+        let now = SystemTime::now();
+        let device = match self.devices.get(local_name) {
+            Some(device) => device,
+            None => self.devices.update(|devices| {
+                let device = devices.entry(local_name.to_string())
+                    .or_insert_with(|| Arc::new(DeviceLiveness { last_seen_ms: AtomicU64::new(0), online: AtomicBool::new(false) }));
+                Arc::clone(device)
+            }),
+        };
+        let now_ms = now.duration_since(UNIX_EPOCH).map_or(0, |now| now.as_millis() as u64);
+        device.last_seen_ms.store(now_ms, Ordering::Relaxed);
+        if !device.online.swap(true, Ordering::AcqRel) {
+            self.notify(local_name, true, now);
+        }
+    }
+
+    /// Marks devices that were not seen within their staleness timeout as offline.
+    pub fn expire(&self, stale_after: impl Fn(&str) -> Duration) {
+        for (local_name, device) in self.devices.snapshot().iter() {
+            let last_seen = device.last_seen();
+            if is_fresh(last_seen, stale_after(local_name)) {
+                continue;
+            }
+            // unless it was seen again in the meantime, or someone else got to it first
+            if device.online.compare_exchange(true, false, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+                self.notify(local_name, false, last_seen);
+            }
+        }
+    }
+
+    pub fn get(&self, local_name: &str, stale_after: Duration) -> Liveness {
+        match self.devices.get(local_name) {
+            Some(device) => Liveness {
+                online: is_fresh(device.last_seen(), stale_after),
+                last_seen: Some(device.last_seen()),
+            },
+            None => Liveness { online: false, last_seen: None },
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<LivenessChange> {
+        self.changes.subscribe()
+    }
+
+    fn notify(&self, local_name: &str, online: bool, last_seen: SystemTime) {
+        info!("Device {} is {}", local_name, if online { "online" } else { "offline" });
+        // nobody might be listening, which is fine
+        let _ = self.changes.send(LivenessChange {
+            local_name: local_name.to_string(),
+            online,
+            last_seen,
+            timestamp: SystemTime::now(),
+        });
+    }
+}
+
+fn is_fresh(last_seen: SystemTime, stale_after: Duration) -> bool {
+    match last_seen.elapsed() {
+        Ok(age) => age <= stale_after,
+        // clock went backwards
+        Err(_) => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transitions_are_emitted() {
+        let tracker = LivenessTracker::new();
+        let mut changes = tracker.subscribe();
+
+        tracker.mark_seen("GVH5075_6A19");
+        tracker.mark_seen("GVH5075_6A19");
+        tracker.expire(|_| Duration::from_secs(60));
+        assert!(tracker.get("GVH5075_6A19", Duration::from_secs(60)).online);
+        tracker.expire(|_| Duration::ZERO);
+        assert!(!tracker.get("GVH5075_6A19", Duration::ZERO).online);
+        tracker.mark_seen("GVH5075_6A19");
+
+        let transitions: Vec<bool> = (0..3).map(|_| changes.try_recv().expect("missing change").online).collect();
+        assert_eq!(transitions, vec![true, false, true]);
+        assert!(changes.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_advertisements_of_known_devices_do_not_copy_the_map() {
+        let tracker = LivenessTracker::new();
+        tracker.mark_seen("GVH5075_6A19");
+        let snapshot = tracker.devices.snapshot();
+        tracker.mark_seen("GVH5075_6A19");
+        tracker.expire(|_| Duration::ZERO);
+        tracker.mark_seen("GVH5075_6A19");
+        assert!(Arc::ptr_eq(&snapshot, &tracker.devices.snapshot()));
+        assert!(tracker.get("GVH5075_6A19", Duration::from_secs(60)).online);
+    }
+
+    #[test]
+    fn test_unknown_device_is_offline() {
+        let tracker = LivenessTracker::new();
+        let liveness = tracker.get("GVH5075_6A19", Duration::from_secs(60));
+        assert!(!liveness.online);
+        assert!(liveness.last_seen.is_none());
+    }
+}
@@ -0,0 +1,266 @@
+use std::collections::VecDeque;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+use super::govee_h5075::DeviceData;
+
+/// How many readings a subscriber can fall behind before it starts losing them.
+pub const READINGS_CAPACITY: usize = 1024;
+
+/// How many of the latest readings are kept around for clients resuming their streams.
+pub const REPLAY_BUFFER_CAPACITY: usize = 4096;
+
+/// A freshly decoded reading of a device.
+#[derive(Debug, Clone)]
+pub struct Reading {
+    pub sequence_number: u64,
+    pub local_name: String,
+    pub data: ReadingData,
+    /// Received before the service was restarted, rather than from an advertisement since then.
+    pub restored: bool,
+}
+
+/// The values of a reading, which virtual devices only have some of.
+#[derive(Debug, Copy, Clone)]
+pub enum ReadingData {
+    /// Decoded from an advertisement of the device.
+    Measured(DeviceData),
+    /// Computed from the readings of other devices.
+    Derived(DerivedData),
+}
+
+/// The values of a virtual device, of which it only has the ones it has expressions for.
+#[derive(Debug, Copy, Clone)]
+pub struct DerivedData {
+    temperature_in_c: Option<f32>,
+    humidity: Option<f32>,
+    last_update_timestamp: SystemTime,
+}
+
+impl DerivedData {
+    /// Computed just now, and rounded to tenths like the values the devices report.
+    pub fn new(temperature_in_c: Option<f32>, humidity: Option<f32>) -> DerivedData {
+        DerivedData::restored(temperature_in_c, humidity, SystemTime::now())
+    }
+
+    fn restored(temperature_in_c: Option<f32>, humidity: Option<f32>, last_update_timestamp: SystemTime) -> DerivedData {
+        let round = |value: f32| (value * 10.0).round() / 10.0;
+        DerivedData {
+            temperature_in_c: temperature_in_c.map(round),
+            humidity: humidity.map(round),
+            last_update_timestamp,
+        }
+    }
+}
+
+impl ReadingData {
+    /// A reading received before the service was restarted, which was measured if it has all the values.
+    pub fn restored(
+        temperature_in_c: Option<f32>,
+        humidity: Option<f32>,
+        battery: Option<u8>,
+        last_update_timestamp: SystemTime,
+    ) -> ReadingData {
+        match (temperature_in_c, humidity, battery) {
+            (Some(temperature_in_c), Some(humidity), Some(battery)) => {
+                ReadingData::Measured(DeviceData::restored(temperature_in_c, humidity, battery, last_update_timestamp))
+            }
+            _ => ReadingData::Derived(DerivedData::restored(temperature_in_c, humidity, last_update_timestamp)),
+        }
+    }
+
+    pub fn temperature_in_c(&self) -> Option<f32> {
+        match self {
+            ReadingData::Measured(data) => Some(data.temperature_in_c()),
+            ReadingData::Derived(data) => data.temperature_in_c,
+        }
+    }
+
+    pub fn humidity(&self) -> Option<f32> {
+        match self {
+            ReadingData::Measured(data) => Some(data.humidity()),
+            ReadingData::Derived(data) => data.humidity,
+        }
+    }
+
+    pub fn battery(&self) -> Option<u8> {
+        match self {
+            ReadingData::Measured(data) => Some(data.battery()),
+            ReadingData::Derived(_) => None,
+        }
+    }
+
+    pub fn last_update_timestamp(&self) -> SystemTime {
+        match self {
+            ReadingData::Measured(data) => data.last_update_timestamp(),
+            ReadingData::Derived(data) => data.last_update_timestamp,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Reading {
+    pub fn h5075(local_name: &str, temperature_in_c: f32, humidity: f32, battery: u8) -> Reading {
+        let manufacturer_data = DeviceData::encode(temperature_in_c, humidity, battery);
+        Reading {
+            sequence_number: 0,
+            local_name: local_name.to_string(),
+            data: ReadingData::Measured(DeviceData::decode(&manufacturer_data).unwrap()),
+            restored: false,
+        }
+    }
+}
+
+/// Assigns sequence numbers to readings and keeps the latest ones for replaying.
+pub struct ReplayBuffer {
+    readings: VecDeque<Reading>,
+    capacity: usize,
+    first_sequence_number: u64,
+    next_sequence_number: u64,
+}
+
+impl ReplayBuffer {
+    pub fn new(capacity: usize) -> ReplayBuffer {
+        // start from the current time (in microseconds), so that sequence numbers keep increasing across restarts
+        let first_sequence_number = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(1, |d| d.as_micros() as u64);
+        ReplayBuffer::starting_at(capacity, first_sequence_number)
+    }
+
+    fn starting_at(capacity: usize, first_sequence_number: u64) -> ReplayBuffer {
+        ReplayBuffer {
+            readings: VecDeque::with_capacity(capacity),
+            capacity,
+            first_sequence_number,
+            next_sequence_number: first_sequence_number,
+        }
+    }
+
+    pub fn push(&mut self, local_name: String, data: ReadingData) -> Reading {
+        let reading = Reading { sequence_number: self.next_sequence_number, local_name, data, restored: false };
+        self.next_sequence_number += 1;
+        if self.readings.len() == self.capacity {
+            self.readings.pop_front();
+        }
+        self.readings.push_back(reading.clone());
+        reading
+    }
+
+    /// Returns all readings that came after the given one,
+    /// or `None` if some of them are no longer available (or the sequence number is unknown).
+    pub fn since(&self, sequence_number: u64) -> Option<Vec<Reading>> {
+        // the reading is either from before a restart, or from the future
+        if sequence_number < self.first_sequence_number || sequence_number >= self.next_sequence_number {
+            return None;
+        }
+        let oldest = self.readings.front().map_or(self.next_sequence_number, |reading| reading.sequence_number);
+        if sequence_number + 1 < oldest {
+            return None;
+        }
+        Some(self.readings.iter().filter(|reading| reading.sequence_number > sequence_number).cloned().collect())
+    }
+}
+
+/// Receives every reading published by the collector, reporting the ones it was too slow to receive.
+pub struct ReadingSubscriber {
+    name: String,
+    receiver: broadcast::Receiver<Reading>,
+    skipped: u64,
+}
+
+impl ReadingSubscriber {
+    pub fn new(name: String, receiver: broadcast::Receiver<Reading>) -> ReadingSubscriber {
+        ReadingSubscriber { name, receiver, skipped: 0 }
+    }
+
+    /// Waits for the next reading. Returns `None` once the collector is gone.
+    pub async fn recv(&mut self) -> Option<Reading> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(reading) => return Some(reading),
+                Err(RecvError::Lagged(skipped)) => {
+                    self.skipped += skipped;
+                    warn!("Subscriber {} is lagging behind, skipped {} readings ({} in total)",
+                        self.name, skipped, self.skipped);
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// Waits for the next reading that satisfies the predicate.
+    pub async fn recv_matching(&mut self, predicate: impl Fn(&Reading) -> bool) -> Option<Reading> {
+        loop {
+            let reading = self.recv().await?;
+            if predicate(&reading) {
+                return Some(reading);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(local_name: &str) -> Reading {
+        Reading::h5075(local_name, 21.5, 40.0, 100)
+    }
+
+    #[test]
+    fn test_only_readings_with_all_values_are_restored_as_measured() {
+        let timestamp = SystemTime::now();
+        let measured = ReadingData::restored(Some(-4.8), Some(53.8), Some(100), timestamp);
+        assert!(matches!(measured, ReadingData::Measured(data) if data.temperature_in_c() == -4.8 && data.battery() == 100));
+        let derived = ReadingData::restored(Some(21.25), None, None, timestamp);
+        assert!(matches!(derived, ReadingData::Derived(_)));
+        assert_eq!((derived.temperature_in_c(), derived.humidity(), derived.battery()), (Some(21.3), None, None));
+        assert_eq!(derived.last_update_timestamp(), timestamp);
+    }
+
+    #[test]
+    fn test_replay_buffer_returns_missed_readings() {
+        let mut buffer = ReplayBuffer::starting_at(3, 100);
+        assert_eq!(buffer.since(99).map(|readings| readings.len()), None);
+        assert_eq!(buffer.since(100).map(|readings| readings.len()), None);
+        for local_name in ["A", "B", "C", "D"] {
+            buffer.push(local_name.to_string(), reading(local_name).data);
+        }
+        let names = |readings: Vec<Reading>| readings.into_iter().map(|r| r.local_name).collect::<Vec<_>>();
+        assert_eq!(buffer.since(103).map(names), Some(vec![]));
+        assert_eq!(buffer.since(101).map(names), Some(vec!["C".to_string(), "D".to_string()]));
+        assert_eq!(buffer.since(100).map(names), Some(vec!["B".to_string(), "C".to_string(), "D".to_string()]));
+        // "A" is gone
+        assert_eq!(buffer.since(99).map(names), None);
+        // not assigned yet
+        assert_eq!(buffer.since(104).map(names), None);
+    }
+
+    #[tokio::test]
+    async fn test_lagging_subscriber_reports_skipped_readings() {
+        let (sender, receiver) = broadcast::channel(2);
+        let mut subscriber = ReadingSubscriber::new("test".to_string(), receiver);
+        for local_name in ["A", "B", "C", "D"] {
+            sender.send(reading(local_name)).unwrap();
+        }
+        drop(sender);
+        assert_eq!(subscriber.recv().await.map(|r| r.local_name), Some("C".to_string()));
+        assert_eq!(subscriber.skipped, 2);
+        assert_eq!(subscriber.recv().await.map(|r| r.local_name), Some("D".to_string()));
+        assert!(subscriber.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_recv_matching_skips_other_readings() {
+        let (sender, receiver) = broadcast::channel(8);
+        let mut subscriber = ReadingSubscriber::new("test".to_string(), receiver);
+        for local_name in ["A", "B", "C"] {
+            sender.send(reading(local_name)).unwrap();
+        }
+        let actual = subscriber.recv_matching(|r| r.local_name == "B").await;
+        assert_eq!(actual.map(|r| r.local_name), Some("B".to_string()));
+    }
+}
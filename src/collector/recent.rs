@@ -0,0 +1,166 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, SystemTime};
+
+/// How many readings are kept at most for every device, however often it advertises.
+const MAX_READINGS_PER_DEVICE: usize = 64 * 1024;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RecentReading {
+    pub timestamp: SystemTime,
+    pub temperature_in_c: Option<f32>,
+    pub humidity: Option<f32>,
+}
+
+/// The lowest and highest values within a window of time, like the ones shown on the display of the H5075.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Extremes {
+    pub min_temperature_in_c: Option<f32>,
+    pub max_temperature_in_c: Option<f32>,
+    pub min_humidity: Option<f32>,
+    pub max_humidity: Option<f32>,
+}
+
+impl Extremes {
+    pub fn of(readings: &[RecentReading]) -> Extremes {
+        let mut extremes = Extremes::default();
+        for reading in readings {
+            if let Some(temperature_in_c) = reading.temperature_in_c {
+                widen(&mut extremes.min_temperature_in_c, &mut extremes.max_temperature_in_c, temperature_in_c);
+            }
+            if let Some(humidity) = reading.humidity {
+                widen(&mut extremes.min_humidity, &mut extremes.max_humidity, humidity);
+            }
+        }
+        extremes
+    }
+}
+
+/// Keeps the readings of every device within a window of time, oldest first.
+pub struct RecentReadings {
+    window: Duration,
+    devices: HashMap<String, VecDeque<RecentReading>>,
+}
+
+impl RecentReadings {
+    pub fn new(window: Duration) -> RecentReadings {
+        RecentReadings { window, devices: HashMap::new() }
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Adds the latest reading of a device, forgetting the ones that fell out of the window.
+    pub fn push(&mut self, local_name: &str, reading: RecentReading) {
+        if self.window.is_zero() {
+            return;
+        }
+        let readings = self.devices.entry(local_name.to_string()).or_default();
+        forget_older(readings, reading.timestamp.checked_sub(self.window));
+        // if the clock was set back, the readings that now seem to be from the future are dropped to keep them in order
+        while readings.back().is_some_and(|last| last.timestamp > reading.timestamp) {
+            readings.pop_back();
+        }
+        readings.push_back(reading);
+    }
+
+    /// Forgets the readings that fell out of the window by now, along with the devices that are left without any,
+    /// or that are no longer to be kept, so that devices which went quiet (or were removed) don't keep theirs forever.
+    pub fn expire(&mut self, now: SystemTime, keep: impl Fn(&str) -> bool) {
+        let oldest = now.checked_sub(self.window);
+        self.devices.retain(|local_name, readings| {
+            forget_older(readings, oldest);
+            !readings.is_empty() && keep(local_name)
+        });
+    }
+
+    /// Copies the readings of the device since the given time, finding the first of them by bisection,
+    /// so that only the copying takes time in proportion to the window.
+    pub fn since(&self, local_name: &str, since: SystemTime) -> Vec<RecentReading> {
+        let readings = match self.devices.get(local_name) {
+            Some(readings) => readings,
+            None => return vec![],
+        };
+        let first = readings.partition_point(|reading| reading.timestamp < since);
+        readings.range(first..).copied().collect()
+    }
+}
+
+/// Also forgets the oldest readings beyond the most that are kept for a device.
+fn forget_older(readings: &mut VecDeque<RecentReading>, oldest: Option<SystemTime>) {
+    while let Some(first) = readings.front() {
+        if readings.len() < MAX_READINGS_PER_DEVICE && oldest.is_none_or(|oldest| first.timestamp >= oldest) {
+            break;
+        }
+        readings.pop_front();
+    }
+}
+
+fn widen(min: &mut Option<f32>, max: &mut Option<f32>, value: f32) {
+    *min = Some(min.map_or(value, |min| min.min(value)));
+    *max = Some(max.map_or(value, |max| max.max(value)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(timestamp: SystemTime, temperature_in_c: f32, humidity: Option<f32>) -> RecentReading {
+        RecentReading { timestamp, temperature_in_c: Some(temperature_in_c), humidity }
+    }
+
+    #[test]
+    fn test_readings_fall_out_of_the_window() {
+        let start = SystemTime::now();
+        let minutes = |minutes: u64| start + Duration::from_secs(minutes * 60);
+        let mut recent = RecentReadings::new(Duration::from_secs(60 * 60));
+        recent.push("GVH5075_0001", reading(minutes(0), 20.0, Some(40.0)));
+        recent.push("GVH5075_0001", reading(minutes(30), 22.0, None));
+        recent.push("GVH5075_0001", reading(minutes(70), 21.0, Some(45.0)));
+        assert_eq!(recent.since("GVH5075_0001", start).iter().map(|reading| reading.temperature_in_c).collect::<Vec<_>>(),
+            vec![Some(22.0), Some(21.0)]);
+        assert_eq!(recent.since("GVH5075_0001", minutes(60)).len(), 1);
+        assert!(recent.since("GVH5075_0002", start).is_empty());
+        assert_eq!(Extremes::of(&recent.since("GVH5075_0001", start)), Extremes {
+            min_temperature_in_c: Some(21.0),
+            max_temperature_in_c: Some(22.0),
+            min_humidity: Some(45.0),
+            max_humidity: Some(45.0),
+        });
+        assert_eq!(Extremes::of(&recent.since("GVH5075_0002", start)), Extremes::default());
+    }
+
+    #[test]
+    fn test_quiet_and_removed_devices_are_forgotten() {
+        let start = SystemTime::now();
+        let minutes = |minutes: u64| start + Duration::from_secs(minutes * 60);
+        let mut recent = RecentReadings::new(Duration::from_secs(60 * 60));
+        recent.push("GVH5075_0001", reading(minutes(0), 20.0, None));
+        recent.push("GVH5075_0002", reading(minutes(0), 21.0, None));
+        recent.push("GVH5075_0002", reading(minutes(50), 21.5, None));
+        recent.push("GVH5075_0003", reading(minutes(50), 22.0, None));
+        recent.expire(minutes(70), |local_name| local_name != "GVH5075_0003");
+        // the first device went quiet, and the third one was removed
+        let mut kept: Vec<&str> = recent.devices.keys().map(String::as_str).collect();
+        kept.sort();
+        assert_eq!(kept, vec!["GVH5075_0002"]);
+        assert_eq!(recent.since("GVH5075_0002", start).len(), 1);
+        recent.expire(minutes(120), |_| true);
+        assert!(recent.devices.is_empty());
+    }
+
+    #[test]
+    fn test_readings_are_kept_in_order() {
+        let start = SystemTime::now();
+        let minutes = |minutes: u64| start + Duration::from_secs(minutes * 60);
+        let mut recent = RecentReadings::new(Duration::from_secs(60 * 60));
+        for minute in 0..50 {
+            recent.push("GVH5075_0001", reading(minutes(minute), minute as f32, None));
+        }
+        assert_eq!(recent.since("GVH5075_0001", minutes(45)).len(), 5);
+        // the clock was set back by 10 minutes
+        recent.push("GVH5075_0001", reading(minutes(40), -1.0, None));
+        let temperatures = recent.since("GVH5075_0001", minutes(38)).iter().map(|reading| reading.temperature_in_c).collect::<Vec<_>>();
+        assert_eq!(temperatures, vec![Some(38.0), Some(39.0), Some(40.0), Some(-1.0)]);
+    }
+}
@@ -0,0 +1,86 @@
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use futures::stream::{self, StreamExt};
+use tokio::time::{Instant, sleep_until};
+
+use super::{AdvertisementSource, AdvertisementStream};
+use super::btsnoop::{is_btsnoop, parse_btsnoop};
+use super::capture::{CaptureRecord, parse_capture};
+
+/// How fast a capture is replayed, relative to the pace it was recorded at.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ReplaySpeed {
+    Factor(f64),
+    Unlimited,
+}
+
+#[derive(Debug, thiserror::Error, Eq, PartialEq)]
+pub enum ReplaySpeedError {
+    #[error("replay speed must be a positive number or \"max\", got {0:?}")]
+    Invalid(String),
+}
+
+impl FromStr for ReplaySpeed {
+    type Err = ReplaySpeedError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "max" => Ok(ReplaySpeed::Unlimited),
+            _ => match s.parse::<f64>() {
+                Ok(factor) if factor.is_finite() && factor > 0.0 => Ok(ReplaySpeed::Factor(factor)),
+                _ => Err(ReplaySpeedError::Invalid(s.to_string())),
+            },
+        }
+    }
+}
+
+/// Feeds advertisements from a capture file (or a btsnoop HCI log), preserving (or scaling) the original timing.
+pub struct ReplaySource {
+    records: Vec<CaptureRecord>,
+    speed: ReplaySpeed,
+}
+
+impl ReplaySource {
+    pub fn open(path: &Path, speed: ReplaySpeed) -> Result<ReplaySource, Box<dyn Error>> {
+        let bytes = fs::read(path)?;
+        let records = if is_btsnoop(&bytes) {
+            parse_btsnoop(&bytes)?
+        } else {
+            parse_capture(bytes.as_slice())?
+        };
+        info!("Loaded {} advertisements from {:?}", records.len(), path);
+        Ok(ReplaySource { records, speed })
+    }
+}
+
+#[tonic::async_trait]
+impl AdvertisementSource for ReplaySource {
+    async fn advertisements(&self) -> Result<AdvertisementStream, Box<dyn Error>> {
+        let speed = self.speed;
+        let start = Instant::now();
+        let records = self.records.clone();
+        Ok(Box::pin(stream::iter(records).then(move |record| async move {
+            if let ReplaySpeed::Factor(factor) = speed {
+                sleep_until(start + record.elapsed().div_f64(factor)).await;
+            }
+            record.advertisement
+        })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay_speed_parses() {
+        assert_eq!("max".parse(), Ok(ReplaySpeed::Unlimited));
+        assert_eq!("1".parse(), Ok(ReplaySpeed::Factor(1.0)));
+        assert_eq!("2.5".parse(), Ok(ReplaySpeed::Factor(2.5)));
+        assert_eq!("0".parse::<ReplaySpeed>(), Err(ReplaySpeedError::Invalid("0".to_string())));
+        assert_eq!("fast".parse::<ReplaySpeed>(), Err(ReplaySpeedError::Invalid("fast".to_string())));
+    }
+}
@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::f64::consts::PI;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::stream;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use serde::Deserialize;
+use tokio::time::{Instant, sleep_until};
+
+use crate::device_database::Model;
+
+use super::{Advertisement, AdvertisementSource, AdvertisementStream};
+use super::govee_h5075::DeviceData;
+
+const SECONDS_PER_DAY: f64 = 24.0 * 60.0 * 60.0;
+
+/// A value that follows a daily sine curve (peaking at `peak_hour` UTC) with some random noise on top.
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DiurnalCurve {
+    mean: f64,
+    #[serde(default)]
+    amplitude: f64,
+    #[serde(default)]
+    peak_hour: f64,
+    #[serde(default)]
+    noise: f64,
+}
+
+impl DiurnalCurve {
+    fn check(&self) -> Result<(), String> {
+        for (name, value) in [("mean", self.mean), ("amplitude", self.amplitude), ("peak_hour", self.peak_hour)] {
+            if !value.is_finite() {
+                return Err(format!("{} has to be a finite number", name));
+            }
+        }
+        if !self.noise.is_finite() || self.noise < 0.0 {
+            return Err("noise has to be a finite, non-negative number".to_string());
+        }
+        Ok(())
+    }
+
+    fn sample(&self, rng: &mut StdRng, seconds_of_day: f64) -> f64 {
+        let phase = 2.0 * PI * (seconds_of_day / 3600.0 - self.peak_hour) / 24.0;
+        let noise = if self.noise > 0.0 { rng.gen_range(-self.noise..=self.noise) } else { 0.0 };
+        self.mean + self.amplitude * phase.cos() + noise
+    }
+}
+
+/// Periodic intervals during which a device stops advertising altogether.
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Outage {
+    every_secs: u64,
+    duration_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SimulatedDevice {
+    friendly_name: String,
+    #[serde(default)]
+    model: Model,
+    temperature: DiurnalCurve,
+    humidity: DiurnalCurve,
+    #[serde(default = "default_battery")]
+    battery: f64,
+    #[serde(default)]
+    battery_drain_per_day: f64,
+    #[serde(default = "default_advertisement_interval_secs")]
+    advertisement_interval_secs: f64,
+    #[serde(default)]
+    dropout_probability: f64,
+    outage: Option<Outage>,
+}
+
+fn default_battery() -> f64 {
+    100.0
+}
+
+fn default_advertisement_interval_secs() -> f64 {
+    2.0
+}
+
+impl SimulatedDevice {
+    /// Rejects the values that would make the simulation panic (or spin) at runtime.
+    fn check(&self) -> Result<(), String> {
+        self.temperature.check().map_err(|message| format!("temperature: {}", message))?;
+        self.humidity.check().map_err(|message| format!("humidity: {}", message))?;
+        if !self.battery.is_finite() || !self.battery_drain_per_day.is_finite() {
+            return Err("battery and battery_drain_per_day have to be finite numbers".to_string());
+        }
+        if !self.advertisement_interval_secs.is_finite() || self.advertisement_interval_secs <= 0.0 {
+            return Err("advertisement_interval_secs has to be a finite, positive number".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.dropout_probability) {
+            return Err("dropout_probability has to be between 0 and 1".to_string());
+        }
+        Ok(())
+    }
+
+    /// Whether the device advertises at the given time, rather than being in an outage or dropping out.
+    fn advertises(&self, rng: &mut StdRng, elapsed: Duration) -> bool {
+        !self.is_in_outage(elapsed) && !rng.gen_bool(self.dropout_probability)
+    }
+
+    fn is_in_outage(&self, elapsed: Duration) -> bool {
+        match self.outage {
+            Some(Outage { every_secs, duration_secs }) if every_secs > 0 => {
+                elapsed.as_secs() % every_secs >= every_secs.saturating_sub(duration_secs)
+            }
+            _ => false,
+        }
+    }
+
+    fn manufacturer_data(&self, rng: &mut StdRng, elapsed: Duration) -> HashMap<u16, Vec<u8>> {
+        let seconds_of_day = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |d| d.as_secs_f64() % SECONDS_PER_DAY);
+        let temperature = self.temperature.sample(rng, seconds_of_day);
+        let humidity = self.humidity.sample(rng, seconds_of_day).clamp(0.0, 99.9);
+        let battery = (self.battery - self.battery_drain_per_day * elapsed.as_secs_f64() / SECONDS_PER_DAY)
+            .clamp(0.0, 100.0);
+        match self.model {
+            Model::H5075 => DeviceData::encode(temperature as f32, humidity as f32, battery.round() as u8),
+            Model::Virtual => unreachable!("virtual devices are rejected when loading the simulation"),
+        }
+    }
+}
+
+/// Fabricates advertisements for a set of virtual devices.
+pub struct SimulatedSource {
+    devices: HashMap<String, SimulatedDevice>,
+}
+
+impl SimulatedSource {
+    pub fn load(path: &Path) -> Result<SimulatedSource, Box<dyn Error>> {
+        let source = SimulatedSource::parse(&fs::read_to_string(path)?)?;
+        info!("Loaded simulation for {} devices", source.devices.len());
+        Ok(source)
+    }
+
+    fn parse(contents: &str) -> Result<SimulatedSource, Box<dyn Error>> {
+        let devices: HashMap<String, SimulatedDevice> = toml::from_str(contents)?;
+        for (local_name, device) in &devices {
+            if device.model == Model::Virtual {
+                return Err(format!("{} can't be simulated, virtual devices belong in the devices file", local_name).into());
+            }
+            device.check().map_err(|message| format!("invalid simulation of {}: {}", local_name, message))?;
+        }
+        Ok(SimulatedSource { devices })
+    }
+
+    /// Local and friendly names of the simulated devices.
+    pub fn devices(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.devices.iter().map(|(local_name, device)| (local_name, &device.friendly_name))
+    }
+}
+
+struct SimulationState {
+    rng: StdRng,
+    start: Instant,
+    schedule: Vec<(Instant, String, SimulatedDevice)>,
+}
+
+#[tonic::async_trait]
+impl AdvertisementSource for SimulatedSource {
+    async fn advertisements(&self) -> Result<AdvertisementStream, Box<dyn Error>> {
+        let start = Instant::now();
+        let schedule = self.devices.iter()
+            .map(|(local_name, device)| (start, local_name.clone(), device.clone()))
+            .collect();
+        let state = SimulationState { rng: StdRng::from_entropy(), start, schedule };
+        Ok(Box::pin(stream::unfold(state, |mut state| async move {
+            loop {
+                // pick the device that is due to advertise next
+                let (due, local_name, device) = state.schedule.iter_mut().min_by_key(|(due, _, _)| *due)?;
+                sleep_until(*due).await;
+                let elapsed = due.duration_since(state.start);
+                *due += Duration::from_secs_f64(device.advertisement_interval_secs.max(0.1));
+                if !device.advertises(&mut state.rng, elapsed) {
+                    continue;
+                }
+                let advertisement = Advertisement {
+                    address: format!("simulated:{}", local_name),
+                    local_name: Some(local_name.clone()),
+                    rssi: Some(state.rng.gen_range(-90..=-50)),
+                    manufacturer_data: device.manufacturer_data(&mut state.rng, elapsed),
+                    discovered: false,
+                };
+                return Some((advertisement, state));
+            }
+        })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEVICE: &str = r#"
+        [GVH5075_0001]
+        friendly_name = "Simulated Freezer"
+        temperature = { mean = -18.0, noise = 0.5 }
+        humidity = { mean = 30.0 }
+    "#;
+
+    fn device(extra: &str) -> SimulatedDevice {
+        let source = SimulatedSource::parse(&format!("{}{}", DEVICE, extra)).unwrap();
+        source.devices["GVH5075_0001"].clone()
+    }
+
+    #[test]
+    fn test_outages_and_dropouts_are_scheduled() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let secs = Duration::from_secs;
+        let flaky = device("outage = { every_secs = 10, duration_secs = 3 }\n");
+        // the outage takes up the last 3 seconds of every 10
+        assert_eq!((0..20).filter(|&elapsed| !flaky.advertises(&mut rng, secs(elapsed))).collect::<Vec<_>>(),
+            vec![7, 8, 9, 17, 18, 19]);
+        let lossy = device("dropout_probability = 0.5\n");
+        let advertised = (0..1000).filter(|&elapsed| lossy.advertises(&mut rng, secs(elapsed))).count();
+        assert!((400..600).contains(&advertised), "{} advertisements", advertised);
+        assert!((0..100).all(|elapsed| !device("dropout_probability = 1.0\n").advertises(&mut rng, secs(elapsed))));
+    }
+
+    #[test]
+    fn test_values_that_would_panic_are_rejected() {
+        for invalid in [
+            "advertisement_interval_secs = inf\n",
+            "advertisement_interval_secs = 0.0\n",
+            "dropout_probability = nan\n",
+            "dropout_probability = 1.5\n",
+            "battery = -inf\n",
+        ] {
+            assert!(SimulatedSource::parse(&format!("{}{}", DEVICE, invalid)).is_err(), "{}", invalid);
+        }
+        for invalid in ["{ mean = nan }", "{ mean = 20.0, noise = inf }", "{ mean = 20.0, noise = -1.0 }"] {
+            let contents = DEVICE.replace("{ mean = -18.0, noise = 0.5 }", invalid);
+            assert!(SimulatedSource::parse(&contents).is_err(), "{}", invalid);
+        }
+    }
+}
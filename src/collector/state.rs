@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::atomic_write::write_atomically;
+
+use super::readings::ReadingData;
+
+/// Bumped whenever the format changes, so that a state file written by another version isn't misread.
+const STATE_VERSION: u32 = 1;
+
+#[derive(Debug, thiserror::Error)]
+pub enum StateError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("malformed state file: {0}")]
+    Malformed(#[from] serde_json::Error),
+    #[error("the state file is at version {0}, which this version of the service doesn't support")]
+    UnsupportedVersion(u32),
+}
+
+/// What the collector knew when it was last saved, so that it doesn't start out empty after a restart.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedState {
+    version: u32,
+    /// The local names of the peripherals, keyed by their addresses.
+    pub known_devices: HashMap<String, String>,
+    /// The latest reading of every device, keyed by its local name.
+    pub readings: HashMap<String, SavedReading>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedReading {
+    pub timestamp_ms: u64,
+    pub temperature_in_c: Option<f32>,
+    pub humidity: Option<f32>,
+    pub battery: Option<u8>,
+}
+
+impl SavedReading {
+    pub fn of(data: &ReadingData) -> SavedReading {
+        SavedReading {
+            timestamp_ms: data.last_update_timestamp().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64),
+            temperature_in_c: data.temperature_in_c(),
+            humidity: data.humidity(),
+            battery: data.battery(),
+        }
+    }
+
+    pub fn data(&self) -> ReadingData {
+        let timestamp = UNIX_EPOCH + Duration::from_millis(self.timestamp_ms);
+        ReadingData::restored(self.temperature_in_c, self.humidity, self.battery, timestamp)
+    }
+}
+
+impl SavedState {
+    pub fn new(known_devices: HashMap<String, String>, readings: HashMap<String, SavedReading>) -> SavedState {
+        SavedState { version: STATE_VERSION, known_devices, readings }
+    }
+
+    /// Reads the state file, if there is one.
+    pub fn read(path: &Path) -> Result<Option<SavedState>, StateError> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let state: SavedState = serde_json::from_str(&contents)?;
+        if state.version != STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(state.version));
+        }
+        Ok(Some(state))
+    }
+
+    /// Replaces the state file (creating its directory if needed), never leaving it half-written.
+    pub fn write(&self, path: &Path) -> Result<(), StateError> {
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory)?;
+        }
+        write_atomically(path, &serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_is_written_and_read_back() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("state.json");
+        assert_eq!(SavedState::read(&path).unwrap(), None);
+        let reading = SavedReading { timestamp_ms: 1_600_000_000_000, temperature_in_c: Some(-18.5), humidity: Some(70.0), battery: Some(90) };
+        let state = SavedState::new(
+            HashMap::from([("A4:C1:38:00:00:01".to_string(), "GVH5075_0001".to_string())]),
+            HashMap::from([("GVH5075_0001".to_string(), reading)]),
+        );
+        state.write(&path).unwrap();
+        assert_eq!(SavedState::read(&path).unwrap(), Some(state));
+        assert_eq!(SavedReading::of(&reading.data()), reading);
+
+        fs::write(&path, r#"{"version": 42, "known_devices": {}, "readings": {}}"#).unwrap();
+        assert!(matches!(SavedState::read(&path), Err(StateError::UnsupportedVersion(42))));
+        fs::write(&path, "{").unwrap();
+        assert!(matches!(SavedState::read(&path), Err(StateError::Malformed(_))));
+    }
+}
@@ -0,0 +1,344 @@
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use dirs::{config_dir, data_dir};
+use serde::de::IgnoredAny;
+use serde::Deserialize;
+
+use crate::atomic_write::write_atomically;
+use crate::device_database::{DeviceDatabase, DevicesSource};
+use crate::history::{check_tiers, default_tiers, Tier};
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:50051";
+const DEFAULT_LOG_LEVEL: &str = "info";
+const DEFAULT_ADAPTER_TIMEOUT_SECS: u64 = 60;
+const DEFAULT_STALE_AFTER_SECS: u64 = 300;
+const DEFAULT_RECENT_READINGS_HOURS: u64 = 24;
+
+/// Written on the first run of a new install, which thereby starts out in strict mode.
+const DEFAULT_CONFIG: &str = r#"# Created on the first run, see the README for the other settings.
+[service]
+strict_config = true
+
+# Devices added at runtime are saved here.
+[devices]
+"#;
+
+/// Prefix of the environment variables that override the configuration file.
+const ENV_PREFIX: &str = "GOVEE_COLLECTOR_";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("unable to read the configuration file {path:?}: {source}")]
+    Unreadable { path: PathBuf, source: io::Error },
+    #[error("unable to create the configuration file {path:?}: {source}")]
+    Unwritable { path: PathBuf, source: io::Error },
+    #[error("invalid configuration file {path:?}: {message}")]
+    Invalid { path: PathBuf, message: String },
+    #[error("invalid value {value:?} of {name}: {message}")]
+    InvalidVariable { name: String, value: String, message: String },
+}
+
+/// Settings of the service itself, any of which might be left out.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServiceConfig {
+    pub address: Option<SocketAddr>,
+    pub log_level: Option<String>,
+    pub adapter_timeout_secs: Option<u64>,
+    pub stale_after_secs: Option<u64>,
+    /// Zero keeps no recent readings in memory.
+    pub recent_readings_hours: Option<u64>,
+    pub devices_file: Option<PathBuf>,
+    /// Off unless asked for, so that existing installs keep starting with a missing or imperfect devices file.
+    /// New installs get it from the configuration file created on their first run.
+    pub strict_config: Option<bool>,
+    pub history_file: Option<PathBuf>,
+    pub state_file: Option<PathBuf>,
+    /// Only set in the configuration file.
+    pub history_tiers: Option<Vec<Tier>>,
+}
+
+impl ServiceConfig {
+    /// Reads the `GOVEE_COLLECTOR_*` environment variables.
+    pub fn from_env() -> Result<ServiceConfig, ConfigError> {
+        Self::from_variables(|name| env::var(name).ok())
+    }
+
+    fn from_variables(variable: impl Fn(&str) -> Option<String>) -> Result<ServiceConfig, ConfigError> {
+        Ok(ServiceConfig {
+            address: parse_variable(&variable, "ADDRESS")?,
+            log_level: parse_variable(&variable, "LOG_LEVEL")?,
+            adapter_timeout_secs: parse_variable(&variable, "ADAPTER_TIMEOUT")?,
+            stale_after_secs: parse_variable(&variable, "STALE_AFTER")?,
+            recent_readings_hours: parse_variable(&variable, "RECENT_READINGS_HOURS")?,
+            devices_file: parse_variable(&variable, "DEVICES_FILE")?,
+            strict_config: parse_variable(&variable, "STRICT_CONFIG")?,
+            history_file: parse_variable(&variable, "HISTORY_FILE")?,
+            state_file: parse_variable(&variable, "STATE_FILE")?,
+            history_tiers: None,
+        })
+    }
+
+    /// Takes every setting from `self`, unless it's left out there.
+    pub fn or(self, other: ServiceConfig) -> ServiceConfig {
+        ServiceConfig {
+            address: self.address.or(other.address),
+            log_level: self.log_level.or(other.log_level),
+            adapter_timeout_secs: self.adapter_timeout_secs.or(other.adapter_timeout_secs),
+            stale_after_secs: self.stale_after_secs.or(other.stale_after_secs),
+            recent_readings_hours: self.recent_readings_hours.or(other.recent_readings_hours),
+            devices_file: self.devices_file.or(other.devices_file),
+            strict_config: self.strict_config.or(other.strict_config),
+            history_file: self.history_file.or(other.history_file),
+            state_file: self.state_file.or(other.state_file),
+            history_tiers: self.history_tiers.or(other.history_tiers),
+        }
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address.unwrap_or_else(|| DEFAULT_ADDRESS.parse().expect("invalid default address"))
+    }
+
+    pub fn log_level(&self) -> &str {
+        self.log_level.as_deref().unwrap_or(DEFAULT_LOG_LEVEL)
+    }
+
+    pub fn adapter_timeout_secs(&self) -> u64 {
+        self.adapter_timeout_secs.unwrap_or(DEFAULT_ADAPTER_TIMEOUT_SECS)
+    }
+
+    pub fn stale_after_secs(&self) -> u64 {
+        self.stale_after_secs.unwrap_or(DEFAULT_STALE_AFTER_SECS)
+    }
+
+    pub fn recent_readings_hours(&self) -> u64 {
+        self.recent_readings_hours.unwrap_or(DEFAULT_RECENT_READINGS_HOURS)
+    }
+
+    pub fn strict_config(&self) -> bool {
+        self.strict_config.unwrap_or(false)
+    }
+
+    pub fn history_tiers(&self) -> Vec<Tier> {
+        self.history_tiers.clone().unwrap_or_else(default_tiers)
+    }
+}
+
+fn parse_variable<T>(variable: &impl Fn(&str) -> Option<String>, suffix: &str) -> Result<Option<T>, ConfigError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let name = format!("{}{}", ENV_PREFIX, suffix);
+    match variable(&name) {
+        Some(value) => match value.parse() {
+            Ok(parsed) => Ok(Some(parsed)),
+            Err(err) => Err(ConfigError::InvalidVariable { message: err.to_string(), name, value }),
+        },
+        None => Ok(None),
+    }
+}
+
+/// The service configuration file, whose devices section is left to the device database.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFileContents {
+    #[serde(default)]
+    service: ServiceConfig,
+    devices: Option<IgnoredAny>,
+}
+
+/// The settings of the service, with the command line taking precedence over the environment,
+/// and the environment over the configuration file.
+#[derive(Debug)]
+pub struct Config {
+    /// The configuration file the settings were (partly) read from, if any.
+    pub path: Option<PathBuf>,
+    pub service: ServiceConfig,
+    /// Where the devices are described, unless the default devices file should be used.
+    pub devices_source: Option<DevicesSource>,
+}
+
+impl Config {
+    /// Without an explicit path (on the command line or in `GOVEE_COLLECTOR_CONFIG`),
+    /// the first configuration file found in the default locations is used, if any.
+    pub fn load(command_line: ServiceConfig, path: Option<PathBuf>) -> Result<Config, ConfigError> {
+        let overrides = command_line.or(ServiceConfig::from_env()?);
+        let path = path
+            .or_else(|| env::var_os(format!("{}CONFIG", ENV_PREFIX)).map(PathBuf::from))
+            .or_else(|| default_config_paths().into_iter().find(|path| path.exists()));
+        let path = match path {
+            Some(path) => path,
+            None => return Ok(Config::new(overrides, None, false)),
+        };
+        let file_contents = match fs::read_to_string(&path) {
+            Ok(file_contents) => file_contents,
+            Err(source) => return Err(ConfigError::Unreadable { path, source }),
+        };
+        Config::parse(overrides, path, &file_contents)
+    }
+
+    /// Like `load`, except that on a new install, without any configuration file or devices file to be found,
+    /// the default configuration file is created first.
+    ///
+    /// If it can't be created, the service starts as it would have without it.
+    pub fn load_or_create(command_line: ServiceConfig, path: Option<PathBuf>) -> Result<Config, ConfigError> {
+        let config = Config::load(command_line.clone(), path)?;
+        if config.path.is_some() || config.devices_source.is_some() || DeviceDatabase::has_default_devices_file() {
+            return Ok(config);
+        }
+        let path = match default_config_paths().into_iter().next() {
+            Some(path) => path,
+            None => return Ok(config),
+        };
+        match Config::create(command_line.or(ServiceConfig::from_env()?), path) {
+            Ok(created) => Ok(created),
+            Err(err) => {
+                eprintln!("{}", err);
+                Ok(config)
+            }
+        }
+    }
+
+    fn create(overrides: ServiceConfig, path: PathBuf) -> Result<Config, ConfigError> {
+        let directory = path.parent().unwrap_or(Path::new(""));
+        if let Err(source) = fs::create_dir_all(directory).and_then(|()| write_atomically(&path, DEFAULT_CONFIG)) {
+            return Err(ConfigError::Unwritable { path, source });
+        }
+        Config::parse(overrides, path, DEFAULT_CONFIG)
+    }
+
+    fn parse(overrides: ServiceConfig, path: PathBuf, file_contents: &str) -> Result<Config, ConfigError> {
+        let contents: ConfigFileContents = match toml_edit::de::from_str(file_contents) {
+            Ok(contents) => contents,
+            Err(err) => return Err(ConfigError::Invalid { path, message: err.to_string() }),
+        };
+        let has_devices = contents.devices.is_some();
+        if has_devices && contents.service.devices_file.is_some() {
+            let message = "the devices are described in both the devices section and service.devices_file".to_string();
+            return Err(ConfigError::Invalid { path, message });
+        }
+        if let Some(Err(message)) = contents.service.history_tiers.as_deref().map(check_tiers) {
+            return Err(ConfigError::Invalid { path, message });
+        }
+        let mut service = contents.service;
+        // relative to the configuration file rather than to wherever the service happens to be started
+        let directory = path.parent().unwrap_or(Path::new(""));
+        service.devices_file = service.devices_file.map(|devices_file| directory.join(devices_file));
+        service.history_file = service.history_file.map(|history_file| directory.join(history_file));
+        service.state_file = service.state_file.map(|state_file| directory.join(state_file));
+        Ok(Config::new(overrides.or(service), Some(path), has_devices))
+    }
+
+    fn new(service: ServiceConfig, path: Option<PathBuf>, has_devices: bool) -> Config {
+        let devices_source = match (&service.devices_file, &path) {
+            (Some(devices_file), _) => Some(DevicesSource::DevicesFile(devices_file.clone())),
+            (None, Some(path)) if has_devices => Some(DevicesSource::ConfigFile(path.clone())),
+            (None, _) => None,
+        };
+        Config { path, service, devices_source }
+    }
+}
+
+/// Where the readings are kept, unless configured otherwise.
+pub fn default_history_file() -> Option<PathBuf> {
+    data_dir().map(|path| path.join("govee_collector").join("history.sqlite3"))
+}
+
+/// Where the latest readings are saved across restarts, unless configured otherwise.
+pub fn default_state_file() -> Option<PathBuf> {
+    data_dir().map(|path| path.join("govee_collector").join("state.json"))
+}
+
+/// The XDG location comes first, followed by the system-wide one.
+fn default_config_paths() -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = config_dir().map(|path| path.join("govee_collector").join("config.toml")).into_iter().collect();
+    if cfg!(unix) {
+        paths.push(PathBuf::from("/etc/govee_collector/config.toml"));
+    }
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        [service]
+        address = "0.0.0.0:50051"
+        log_level = "debug"
+        stale_after_secs = 600
+
+        [devices.GVH5075_0001]
+        friendly_name = "Living Room"
+    "#;
+
+    #[test]
+    fn test_settings_take_precedence() {
+        let variables = |name: &str| match name {
+            "GOVEE_COLLECTOR_LOG_LEVEL" => Some("warn".to_string()),
+            "GOVEE_COLLECTOR_ADAPTER_TIMEOUT" => Some("5".to_string()),
+            _ => None,
+        };
+        let command_line = ServiceConfig { adapter_timeout_secs: Some(10), ..Default::default() };
+        let overrides = command_line.or(ServiceConfig::from_variables(variables).unwrap());
+        let config = Config::parse(overrides, PathBuf::from("/etc/govee_collector/config.toml"), CONFIG).unwrap();
+        assert_eq!(config.service.address(), "0.0.0.0:50051".parse().unwrap());
+        assert_eq!(config.service.log_level(), "warn");
+        assert_eq!(config.service.adapter_timeout_secs(), 10);
+        assert_eq!(config.service.stale_after_secs(), 600);
+        assert!(!config.service.strict_config());
+        assert_eq!(config.devices_source, Some(DevicesSource::ConfigFile(PathBuf::from("/etc/govee_collector/config.toml"))));
+
+        let overrides = ServiceConfig { devices_file: Some(PathBuf::from("devices.toml")), ..Default::default() };
+        let config = Config::parse(overrides, PathBuf::from("/etc/govee_collector/config.toml"), CONFIG).unwrap();
+        assert_eq!(config.devices_source, Some(DevicesSource::DevicesFile(PathBuf::from("devices.toml"))));
+    }
+
+    #[test]
+    fn test_devices_file_is_relative_to_the_configuration_file() {
+        let path = PathBuf::from("/etc/govee_collector/config.toml");
+        let config = Config::parse(ServiceConfig::default(), path.clone(), "[service]\ndevices_file = \"devices.toml\"\n").unwrap();
+        assert_eq!(config.devices_source, Some(DevicesSource::DevicesFile(PathBuf::from("/etc/govee_collector/devices.toml"))));
+        let config = Config::parse(ServiceConfig::default(), path, "").unwrap();
+        assert_eq!(config.devices_source, None);
+        assert_eq!(config.service, ServiceConfig::default());
+    }
+
+    #[test]
+    fn test_new_installs_are_strict() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("govee_collector").join("config.toml");
+        let config = Config::create(ServiceConfig::default(), path.clone()).unwrap();
+        assert!(config.service.strict_config());
+        assert_eq!(config.devices_source, Some(DevicesSource::ConfigFile(path.clone())));
+        assert_eq!(DeviceDatabase::validate(config.devices_source).unwrap(), (path.clone(), 0));
+
+        let command_line = ServiceConfig { strict_config: Some(false), ..Default::default() };
+        let config = Config::parse(command_line, path.clone(), &fs::read_to_string(&path).unwrap()).unwrap();
+        assert!(!config.service.strict_config());
+    }
+
+    #[test]
+    fn test_invalid_settings_are_rejected() {
+        let path = PathBuf::from("config.toml");
+        for file_contents in [
+            "[service]\ncolour = \"blue\"\n",
+            "[service]\naddress = \"localhost\"\n",
+            "[logging]\n",
+            "[service]\ndevices_file = \"devices.toml\"\n[devices]\n",
+            "[[service.history_tiers]]\nresolution_secs = 60\n",
+        ] {
+            let result = Config::parse(ServiceConfig::default(), path.clone(), file_contents);
+            assert!(matches!(result, Err(ConfigError::Invalid { .. })), "{}", file_contents);
+        }
+        let variables = |name: &str| (name == "GOVEE_COLLECTOR_STALE_AFTER").then(|| "soon".to_string());
+        assert!(matches!(ServiceConfig::from_variables(variables), Err(ConfigError::InvalidVariable { .. })));
+    }
+}
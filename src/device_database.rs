@@ -1,59 +1,558 @@
 use std::collections::HashMap;
 use std::error::Error;
-use std::fs;
-use std::path::PathBuf;
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use dirs::home_dir;
+use dirs::{config_dir, home_dir};
 use serde::Deserialize;
+#[cfg(test)]
 use toml::from_str;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::interval;
 
-#[derive(Deserialize)]
-struct Device {
-    friendly_name: String,
+use crate::snapshot::SnapshotMap;
+use devices_file::DevicesFile;
+pub use devices_file::DevicesFileError;
+pub use expression::{Expression, ExpressionError, Quantity};
+pub use selector::Selector;
+
+mod devices_file;
+mod expression;
+mod selector;
+mod validation;
+
+/// How often the devices file is checked for changes.
+const DEVICES_FILE_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The section of the service configuration file that describes the devices.
+pub const DEVICES_SECTION: &str = "devices";
+
+/// Where the devices are described.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DevicesSource {
+    /// A file with nothing but the devices.
+    DevicesFile(PathBuf),
+    /// The devices section of the service configuration file.
+    ConfigFile(PathBuf),
+}
+
+impl DevicesSource {
+    pub fn path(&self) -> &Path {
+        match self {
+            DevicesSource::DevicesFile(path) | DevicesSource::ConfigFile(path) => path,
+        }
+    }
+
+    fn section(&self) -> Option<&'static str> {
+        match self {
+            DevicesSource::DevicesFile(_) => None,
+            DevicesSource::ConfigFile(_) => Some(DEVICES_SECTION),
+        }
+    }
+}
+
+/// Supported sensor models.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Deserialize)]
+pub enum Model {
+    #[default]
+    H5075,
+    /// Computes its values from the readings of other devices.
+    Virtual,
+}
+
+impl FromStr for Model {
+    type Err = DeviceDatabaseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "H5075" => Ok(Model::H5075),
+            "Virtual" => Ok(Model::Virtual),
+            _ => Err(DeviceDatabaseError::UnsupportedModel(s.to_string())),
+        }
+    }
+}
+
+/// Whether a device measures the inside of the house, or the outside.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Location {
+    Indoor,
+    Outdoor,
 }
 
+impl FromStr for Location {
+    type Err = DeviceDatabaseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "indoor" => Ok(Location::Indoor),
+            "outdoor" => Ok(Location::Outdoor),
+            _ => Err(DeviceDatabaseError::UnsupportedLocation(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Location::Indoor => f.write_str("indoor"),
+            Location::Outdoor => f.write_str("outdoor"),
+        }
+    }
+}
+
+/// Offsets added to every reading of a device, to correct for sensor drift.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Deserialize)]
+pub struct Calibration {
+    #[serde(default)]
+    pub temperature_offset_in_c: f32,
+    #[serde(default)]
+    pub humidity_offset: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Device {
+    pub friendly_name: String,
+    #[serde(default)]
+    pub model: Model,
+    pub room: Option<String>,
+    // negative below ground level
+    pub floor: Option<i32>,
+    pub location: Option<Location>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub notes: Option<String>,
+    #[serde(default)]
+    pub calibration: Calibration,
+    pub stale_after_secs: Option<u64>,
+    // only for virtual devices
+    pub temperature: Option<Expression>,
+    pub humidity: Option<Expression>,
+}
+
+impl Device {
+    pub fn is_virtual(&self) -> bool {
+        self.model == Model::Virtual
+    }
+
+    /// The devices the values of a virtual device are computed from.
+    pub fn inputs(&self) -> Vec<&str> {
+        let mut inputs = vec![];
+        for expression in self.temperature.iter().chain(&self.humidity) {
+            for input in expression.inputs() {
+                if !inputs.contains(&input) {
+                    inputs.push(input);
+                }
+            }
+        }
+        inputs
+    }
+}
+
+/// Tells what's wrong with the expressions of the device, if anything.
+///
+/// Virtual devices can only be computed from physical devices, so that they can't depend on each other in a loop.
+fn check_expressions(local_name: &str, device: &Device, devices: &HashMap<String, Device>) -> Result<(), String> {
+    if !device.is_virtual() {
+        return match device.temperature.is_some() || device.humidity.is_some() {
+            true => Err(format!("{} has expressions, but only virtual devices can have them", local_name)),
+            false => Ok(()),
+        };
+    }
+    if device.temperature.is_none() && device.humidity.is_none() {
+        return Err(format!("virtual device {} needs a temperature or humidity expression", local_name));
+    }
+    for input in device.inputs() {
+        match devices.get(input) {
+            None => return Err(format!("virtual device {} refers to unknown device {}", local_name, input)),
+            Some(input_device) if input_device.is_virtual() => {
+                return Err(format!("virtual device {} refers to virtual device {}", local_name, input));
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DeviceDatabaseError {
+    #[error("unknown device {0}")]
+    UnknownDevice(String),
+    #[error("device {0} already exists")]
+    DeviceExists(String),
+    #[error("unsupported model {0}")]
+    UnsupportedModel(String),
+    #[error("unsupported location {0}, expected indoor or outdoor")]
+    UnsupportedLocation(String),
+    #[error("invalid selector {0:?}, expected room=, floor=, location= or tag= followed by a value")]
+    InvalidSelector(String),
+    #[error("friendly name {0:?} is already used by another device")]
+    FriendlyNameTaken(String),
+    #[error("invalid expression: {0}")]
+    InvalidExpression(#[from] ExpressionError),
+    #[error("{0}")]
+    InvalidVirtualDevice(String),
+    #[error("there is no devices file to save the changes to")]
+    NoDevicesFile,
+    #[error("unable to save the devices file: {0}")]
+    SaveFailed(#[from] io::Error),
+}
+
+#[derive(Default)]
 pub struct DeviceDatabase {
-    local_name_to_device: HashMap<String, Device>,
+    local_name_to_device: SnapshotMap<String, Device>,
+    // also makes sure that changes are saved (and reloaded) in the same order they are applied in
+    devices_file: Mutex<Option<DevicesFile>>,
+    // kept across reloads of the devices file
+    transient_devices: HashMap<String, Device>,
+    // refuses devices files with problems, rather than warning about them
+    strict: bool,
 }
 
 impl DeviceDatabase {
-    pub fn new(devices_file_path: Option<PathBuf>) -> Result<DeviceDatabase, Box<dyn Error>> {
-        let devices = match devices_file_path.or_else(Self::default_devices_file_path) {
-            Some(path) => match fs::read_to_string(&path) {
-                Ok(file_contents) => from_str(&file_contents)?,
-                Err(err) => {
-                    error!("ERROR: Unable to read configuration file at {:?}: {:?}", path, err);
-                    HashMap::new()
+    /// In strict mode, a devices file that is missing or has any problems keeps the service from starting.
+    /// Otherwise, it starts with whatever devices it could load.
+    pub fn new(devices_source: Option<DevicesSource>, strict: bool) -> Result<DeviceDatabase, Box<dyn Error>> {
+        let (devices, devices_file) = match devices_source.or_else(Self::default_devices_source) {
+            Some(devices_source) => match DevicesFile::read(devices_source.clone(), strict) {
+                Ok((devices_file, devices)) => (devices, Some(devices_file)),
+                Err(DevicesFileError::Unreadable { path, source }) if !strict => {
+                    error!("ERROR: Unable to read configuration file at {:?}: {:?}", path, source);
+                    // devices added at runtime (or by hand) will create it
+                    let devices_file = (source.kind() == ErrorKind::NotFound).then(|| DevicesFile::empty(devices_source));
+                    (HashMap::new(), devices_file)
                 },
+                Err(err) => return Err(err.into()),
             },
+            None if strict => return Err(DevicesFileError::NotLocated.into()),
             None => {
                 error!("ERROR: Unable to locate configuration file. Please specify its path explicitly");
-                HashMap::new()
+                (HashMap::new(), None)
             }
         };
         info!("Loaded configuration for {} devices", devices.len());
-        Ok(DeviceDatabase { local_name_to_device: devices })
+        Ok(DeviceDatabase {
+            local_name_to_device: devices.into(),
+            devices_file: Mutex::new(devices_file),
+            transient_devices: HashMap::new(),
+            strict,
+        })
+    }
+
+    /// Checks the devices file the way strict mode does, and returns how many devices it describes.
+    pub fn validate(devices_source: Option<DevicesSource>) -> Result<(PathBuf, usize), DevicesFileError> {
+        let devices_source = devices_source.or_else(Self::default_devices_source).ok_or(DevicesFileError::NotLocated)?;
+        let (devices_file, devices) = DevicesFile::read(devices_source, true)?;
+        Ok((devices_file.path().to_path_buf(), devices.len()))
+    }
+
+    #[cfg(test)]
+    pub fn parse(file_contents: &str) -> DeviceDatabase {
+        let devices: HashMap<String, Device> = from_str(file_contents).expect("invalid configuration");
+        DeviceDatabase { local_name_to_device: devices.into(), ..Default::default() }
+    }
+
+    /// The first devices file that exists, preferring the XDG location to the legacy one in the home directory.
+    ///
+    /// If there is none, the XDG location is where it will be created.
+    fn default_devices_source() -> Option<DevicesSource> {
+        let paths = Self::default_devices_paths();
+        paths.iter()
+            .find(|path| path.exists())
+            .or(paths.first())
+            .cloned()
+            .map(DevicesSource::DevicesFile)
+    }
+
+    /// Whether there is a devices file in any of the default locations.
+    pub fn has_default_devices_file() -> bool {
+        Self::default_devices_paths().iter().any(|path| path.exists())
+    }
+
+    fn default_devices_paths() -> Vec<PathBuf> {
+        let xdg_path = config_dir().map(|path| path.join("govee_collector").join("devices.toml"));
+        let legacy_path = home_dir().map(|path| path.join(".govee_devices.toml"));
+        [xdg_path, legacy_path].into_iter().flatten().collect()
+    }
+
+    /// Adds a device for this run only, without saving it to the devices file.
+    pub fn add_device(&mut self, local_name: String, friendly_name: String) {
+        let device = Device {
+            friendly_name,
+            model: Model::default(),
+            room: None,
+            floor: None,
+            location: None,
+            tags: vec![],
+            notes: None,
+            calibration: Calibration::default(),
+            stale_after_secs: None,
+            temperature: None,
+            humidity: None,
+        };
+        self.local_name_to_device.update(|devices| devices.insert(local_name.clone(), device.clone()));
+        self.transient_devices.insert(local_name, device);
     }
 
-    fn default_devices_file_path() -> Option<PathBuf> {
-        match home_dir() {
-            Some(path) => Some(path.join(".govee_devices.toml")),
-            None => None,
+    /// Reloads the devices file whenever it changes.
+    pub async fn watch_devices_file(&self) {
+        let mut ticks = interval(DEVICES_FILE_POLL_INTERVAL);
+        loop {
+            ticks.tick().await;
+            self.reload(false);
         }
     }
 
-    pub fn contains_device(&self, local_name: &String) -> bool {
-        self.local_name_to_device.contains_key(local_name)
+    /// Reloads the devices file whenever the process receives SIGHUP.
+    #[cfg(unix)]
+    pub async fn reload_on_hangup(&self) -> io::Result<()> {
+        let mut hangups = signal(SignalKind::hangup())?;
+        while hangups.recv().await.is_some() {
+            info!("Received SIGHUP, reloading the devices file");
+            self.reload(true);
+        }
+        Ok(())
+    }
+
+    /// Reads the devices file again if it changed (or regardless of that, when forced).
+    ///
+    /// If the file can't be read, the devices stay as they were.
+    pub fn reload(&self, force: bool) {
+        let mut devices_file = self.devices_file.lock().expect("Could not lock mutex");
+        let devices_file = match devices_file.as_mut() {
+            Some(devices_file) => devices_file,
+            None => return,
+        };
+        if !devices_file.check_changed() && !force {
+            return;
+        }
+        match DevicesFile::read(devices_file.source().clone(), self.strict) {
+            Ok((reloaded, devices)) => {
+                *devices_file = reloaded;
+                self.replace_devices(devices);
+            }
+            Err(err) => error!("Keeping the previous configuration: {}", err),
+        }
+    }
+
+    fn replace_devices(&self, mut devices: HashMap<String, Device>) {
+        devices.extend(self.transient_devices.iter().map(|(local_name, device)| (local_name.clone(), device.clone())));
+        let previous = self.local_name_to_device.snapshot();
+        let added = devices.keys().filter(|local_name| !previous.contains_key(*local_name)).count();
+        let removed = previous.keys().filter(|local_name| !devices.contains_key(*local_name)).count();
+        let changed = devices.iter()
+            .filter(|(local_name, device)| previous.get(*local_name).is_some_and(|previous| previous != *device))
+            .count();
+        info!("Reloaded configuration for {} devices ({} added, {} removed, {} changed)",
+            devices.len(), added, removed, changed);
+        self.local_name_to_device.replace(devices);
+    }
+
+    /// Adds a new device and saves it to the devices file.
+    pub fn insert_device(&self, local_name: &str, device: Device) -> Result<Device, DeviceDatabaseError> {
+        self.change_device(local_name, |old| match old {
+            Some(_) => Err(DeviceDatabaseError::DeviceExists(local_name.to_string())),
+            None => Ok(Some(device)),
+        }).map(|(_, new)| new.expect("device was inserted"))
+    }
+
+    /// Changes an existing device and saves it to the devices file.
+    pub fn update_device(
+        &self,
+        local_name: &str,
+        update: impl FnOnce(&mut Device),
+    ) -> Result<Device, DeviceDatabaseError> {
+        self.change_device(local_name, |old| match old {
+            Some(old) => {
+                let mut device = old.clone();
+                update(&mut device);
+                Ok(Some(device))
+            }
+            None => Err(DeviceDatabaseError::UnknownDevice(local_name.to_string())),
+        }).map(|(_, new)| new.expect("device was updated"))
     }
 
-    pub fn get_friendly_name(&self, local_name: &String) -> Option<&String> {
-        match self.local_name_to_device.get(local_name) {
-            Some(device) => Some(&device.friendly_name),
-            None => None,
+    /// Removes a device, from the devices file as well.
+    pub fn remove_device(&self, local_name: &str) -> Result<Device, DeviceDatabaseError> {
+        self.change_device(local_name, |old| match old {
+            Some(_) => Ok(None),
+            None => Err(DeviceDatabaseError::UnknownDevice(local_name.to_string())),
+        }).map(|(old, _)| old.expect("device was removed"))
+    }
+
+    /// Saves the change to the devices file first, and only applies it once that succeeds.
+    fn change_device(
+        &self,
+        local_name: &str,
+        change: impl FnOnce(Option<&Device>) -> Result<Option<Device>, DeviceDatabaseError>,
+    ) -> Result<(Option<Device>, Option<Device>), DeviceDatabaseError> {
+        let mut devices_file = self.devices_file.lock().expect("Could not lock mutex");
+        let devices_file = devices_file.as_mut().ok_or(DeviceDatabaseError::NoDevicesFile)?;
+        let old = self.local_name_to_device.get(local_name);
+        let new = change(old.as_ref())?;
+        if let Some(new) = new.as_ref().filter(|_| self.strict) {
+            // the file would be refused the next time it's loaded
+            let taken = self.local_name_to_device.snapshot().iter()
+                .any(|(other, device)| other != local_name && device.friendly_name.trim() == new.friendly_name.trim());
+            if taken {
+                return Err(DeviceDatabaseError::FriendlyNameTaken(new.friendly_name.clone()));
+            }
         }
+        self.check_virtual_devices(local_name, new.as_ref())?;
+        devices_file.save_device(local_name, old.as_ref(), new.as_ref())?;
+        info!("Saved device {} to {:?}", local_name, devices_file.path());
+        self.local_name_to_device.update(|devices| match &new {
+            Some(device) => devices.insert(local_name.to_string(), device.clone()),
+            None => devices.remove(local_name),
+        });
+        Ok((old, new))
     }
 
-    pub fn get_all_devices(&self) -> Vec<&String> {
-        self.local_name_to_device.keys().collect()
+    /// Makes sure that the change leaves the virtual devices it affects computable.
+    fn check_virtual_devices(&self, local_name: &str, new: Option<&Device>) -> Result<(), DeviceDatabaseError> {
+        let mut devices = self.local_name_to_device.snapshot().as_ref().clone();
+        match new {
+            Some(device) => devices.insert(local_name.to_string(), device.clone()),
+            None => devices.remove(local_name),
+        };
+        let affected = devices.iter()
+            .filter(|(other, device)| other.as_str() == local_name || device.inputs().contains(&local_name));
+        for (other, device) in affected {
+            check_expressions(other, device, &devices).map_err(DeviceDatabaseError::InvalidVirtualDevice)?;
+        }
+        Ok(())
+    }
+
+    pub fn contains_device(&self, local_name: &str) -> bool {
+        self.local_name_to_device.contains_key(local_name)
     }
-}
\ No newline at end of file
+
+    pub fn get_device(&self, local_name: &str) -> Option<Device> {
+        self.local_name_to_device.get(local_name)
+    }
+
+    pub fn get_friendly_name(&self, local_name: &str) -> Option<String> {
+        self.local_name_to_device.snapshot().get(local_name).map(|device| device.friendly_name.clone())
+    }
+
+    pub fn get_stale_after(&self, local_name: &str) -> Option<Duration> {
+        self.local_name_to_device.snapshot().get(local_name)
+            .and_then(|device| device.stale_after_secs)
+            .map(Duration::from_secs)
+    }
+
+    /// Returns a consistent snapshot of all the devices, keyed by their local names.
+    pub fn get_all_devices(&self) -> Arc<HashMap<String, Device>> {
+        self.local_name_to_device.snapshot()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn test_changes_are_saved_to_the_devices_file() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("devices.toml");
+        fs::write(&path, "[GVH5075_0001]\nfriendly_name = \"Living Room\" # by the window\n").unwrap();
+
+        let device_database = DeviceDatabase::new(Some(DevicesSource::DevicesFile(path.clone())), true).unwrap();
+        device_database.update_device("GVH5075_0001", |device| device.room = Some("living room".to_string())).unwrap();
+        let garage = Device { friendly_name: "Garage".to_string(), ..device_database.get_device("GVH5075_0001").unwrap() };
+        device_database.insert_device("GVH5075_0002", garage.clone()).unwrap();
+        assert!(matches!(
+            device_database.insert_device("GVH5075_0002", garage.clone()),
+            Err(DeviceDatabaseError::DeviceExists(_))
+        ));
+        device_database.remove_device("GVH5075_0001").unwrap();
+        assert!(matches!(device_database.remove_device("GVH5075_0001"), Err(DeviceDatabaseError::UnknownDevice(_))));
+
+        let reloaded = DeviceDatabase::new(Some(DevicesSource::DevicesFile(path.clone())), true).unwrap();
+        assert_eq!(reloaded.get_all_devices().len(), 1);
+        assert_eq!(reloaded.get_device("GVH5075_0002"), Some(garage));
+    }
+
+    #[test]
+    fn test_devices_file_is_reloaded_when_changed() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("devices.toml");
+        fs::write(&path, "[GVH5075_0001]\nfriendly_name = \"Living Room\"\n").unwrap();
+        let mut device_database = DeviceDatabase::new(Some(DevicesSource::DevicesFile(path.clone())), true).unwrap();
+        device_database.add_device("simulated:GVH5075_9999".to_string(), "Simulated".to_string());
+
+        device_database.reload(false);
+        assert_eq!(device_database.get_all_devices().len(), 2);
+
+        fs::write(&path, "[GVH5075_0001]\nfriendly_name = \"Lounge\"\n\n[GVH5075_0002]\nfriendly_name = \"Garage\"\n").unwrap();
+        device_database.reload(false);
+        assert_eq!(device_database.get_friendly_name("GVH5075_0001"), Some("Lounge".to_string()));
+        assert!(device_database.contains_device("GVH5075_0002"));
+        assert!(device_database.contains_device("simulated:GVH5075_9999"));
+
+        // a broken file doesn't take the devices down with it
+        fs::write(&path, "[GVH5075_0002]\nfriendly_name = ").unwrap();
+        device_database.reload(false);
+        assert_eq!(device_database.get_all_devices().len(), 3);
+    }
+
+    #[test]
+    fn test_strict_mode_refuses_problems() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("devices.toml");
+        assert!(DeviceDatabase::new(Some(DevicesSource::DevicesFile(path.clone())), true).is_err());
+        assert!(DeviceDatabase::new(Some(DevicesSource::DevicesFile(path.clone())), false).unwrap().get_all_devices().is_empty());
+
+        fs::write(&path, "[GVH5075_0001]\nfriendly_name = \"Living Room\"\ncolour = \"blue\"\n").unwrap();
+        assert!(DeviceDatabase::new(Some(DevicesSource::DevicesFile(path.clone())), true).is_err());
+        assert!(DeviceDatabase::new(Some(DevicesSource::DevicesFile(path.clone())), false).unwrap().contains_device("GVH5075_0001"));
+
+        fs::write(&path, "[GVH5075_0001]\nfriendly_name = \"Living Room\"\n").unwrap();
+        let device_database = DeviceDatabase::new(Some(DevicesSource::DevicesFile(path.clone())), true).unwrap();
+        let garage = Device { friendly_name: "Living Room".to_string(), ..device_database.get_device("GVH5075_0001").unwrap() };
+        let result = device_database.insert_device("GVH5075_0002", garage);
+        assert!(matches!(result, Err(DeviceDatabaseError::FriendlyNameTaken(_))));
+
+        // nor does it accept them when reloading
+        fs::write(&path, "[GVH5075_0001]\nfriendly_name = \"\"\n").unwrap();
+        device_database.reload(true);
+        assert_eq!(device_database.get_friendly_name("GVH5075_0001"), Some("Living Room".to_string()));
+    }
+
+    #[test]
+    fn test_virtual_devices_stay_computable() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("devices.toml");
+        fs::write(&path, "[GVH5075_0001]\nfriendly_name = \"Living Room\"\n").unwrap();
+        let device_database = DeviceDatabase::new(Some(DevicesSource::DevicesFile(path.clone())), true).unwrap();
+        let virtual_device = |friendly_name: &str, temperature: &str| Device {
+            friendly_name: friendly_name.to_string(),
+            model: Model::Virtual,
+            temperature: Some(temperature.parse().unwrap()),
+            ..device_database.get_device("GVH5075_0001").unwrap()
+        };
+        let result = device_database.insert_device("delta", virtual_device("Delta", "GVH5075_0001.temperature - outdoor.temperature"));
+        assert!(matches!(result, Err(DeviceDatabaseError::InvalidVirtualDevice(_))));
+        device_database.insert_device("double", virtual_device("Double", "GVH5075_0001.temperature * 2")).unwrap();
+        assert_eq!(device_database.get_device("double").unwrap().inputs(), vec!["GVH5075_0001"]);
+
+        let result = device_database.remove_device("GVH5075_0001");
+        assert!(matches!(result, Err(DeviceDatabaseError::InvalidVirtualDevice(_))));
+        assert!(device_database.contains_device("GVH5075_0001"));
+    }
+
+    #[test]
+    fn test_changes_need_a_devices_file() {
+        let device_database = DeviceDatabase::parse("[GVH5075_0001]\nfriendly_name = \"Living Room\"\n");
+        let result = device_database.update_device("GVH5075_0001", |device| device.friendly_name.clear());
+        assert!(matches!(result, Err(DeviceDatabaseError::NoDevicesFile)));
+        assert_eq!(device_database.get_friendly_name("GVH5075_0001"), Some("Living Room".to_string()));
+    }
+}
@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use toml_edit::{Array, DocumentMut, InlineTable, Item, Table, TableLike, Value};
+
+use crate::atomic_write::write_atomically;
+
+use super::validation::{parse_devices, Diagnostic};
+use super::{Calibration, Device, DevicesSource, Model};
+
+#[derive(Debug, thiserror::Error)]
+pub enum DevicesFileError {
+    #[error("unable to locate the devices file, please specify its path explicitly")]
+    NotLocated,
+    #[error("unable to read {path:?}: {source}")]
+    Unreadable { path: PathBuf, source: io::Error },
+    #[error("invalid devices file {path:?}: {}", join(problems))]
+    Invalid { path: PathBuf, problems: Vec<Diagnostic> },
+}
+
+fn join(problems: &[Diagnostic]) -> String {
+    problems.iter().map(Diagnostic::to_string).collect::<Vec<_>>().join("; ")
+}
+
+/// Tells whether the file changed since it was last looked at.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct Fingerprint {
+    modified: Option<SystemTime>,
+    len: u64,
+}
+
+impl Fingerprint {
+    fn of(path: &Path) -> Option<Fingerprint> {
+        let metadata = fs::metadata(path).ok()?;
+        Some(Fingerprint { modified: metadata.modified().ok(), len: metadata.len() })
+    }
+}
+
+/// The TOML file the devices were loaded from.
+///
+/// Changes are applied to the parsed document rather than to a freshly serialized one,
+/// so that comments, formatting and the order of the devices survive.
+pub struct DevicesFile {
+    source: DevicesSource,
+    document: DocumentMut,
+    fingerprint: Option<Fingerprint>,
+}
+
+impl DevicesFile {
+    /// In strict mode, any problem with the devices makes the whole file invalid.
+    /// Otherwise, they are only logged.
+    pub fn read(source: DevicesSource, strict: bool) -> Result<(DevicesFile, HashMap<String, Device>), DevicesFileError> {
+        let path = source.path().to_path_buf();
+        let fingerprint = Fingerprint::of(&path);
+        let file_contents = match fs::read_to_string(&path) {
+            Ok(file_contents) => file_contents,
+            Err(source) => return Err(DevicesFileError::Unreadable { path, source }),
+        };
+        let parsed = match parse_devices(&file_contents, source.section().is_some()) {
+            Ok(parsed) => parsed,
+            Err(diagnostic) => return Err(DevicesFileError::Invalid { path, problems: vec![diagnostic] }),
+        };
+        if strict && !parsed.problems.is_empty() {
+            return Err(DevicesFileError::Invalid { path, problems: parsed.problems });
+        }
+        for problem in &parsed.problems {
+            warn!("{:?}, {}", path, problem);
+        }
+        Ok((DevicesFile { source, document: parsed.document, fingerprint }, parsed.devices))
+    }
+
+    /// A file that doesn't exist yet, and will be created once the first change is saved.
+    pub fn empty(source: DevicesSource) -> DevicesFile {
+        DevicesFile { source, document: DocumentMut::new(), fingerprint: None }
+    }
+
+    pub fn source(&self) -> &DevicesSource {
+        &self.source
+    }
+
+    pub fn path(&self) -> &Path {
+        self.source.path()
+    }
+
+    /// Checks whether the file was changed by someone else since it was last read or saved.
+    ///
+    /// Every change is only reported once, whether it's going to be read successfully or not.
+    pub fn check_changed(&mut self) -> bool {
+        let fingerprint = Fingerprint::of(self.path());
+        fingerprint != std::mem::replace(&mut self.fingerprint, fingerprint)
+    }
+
+    /// Replaces the device (or removes it, if there is no new one) and saves the file.
+    ///
+    /// Nothing changes if the file can't be saved.
+    pub fn save_device(&mut self, local_name: &str, old: Option<&Device>, new: Option<&Device>) -> io::Result<()> {
+        let mut document = self.document.clone();
+        let table = match self.source.section() {
+            Some(section) => {
+                if !document.get(section).is_some_and(Item::is_table_like) {
+                    let mut table = Table::new();
+                    // only the tables of the devices are written, as [devices.<name>]
+                    table.set_implicit(true);
+                    document.insert(section, Item::Table(table));
+                }
+                document[section].as_table_like_mut().expect("section must be a table")
+            }
+            None => document.as_table_mut() as &mut dyn TableLike,
+        };
+        edit_device(table, local_name, old, new);
+        write_atomically(self.path(), &document.to_string())?;
+        self.document = document;
+        // don't mistake our own change for someone else's
+        self.fingerprint = Fingerprint::of(self.path());
+        Ok(())
+    }
+}
+
+/// Extracts the value to be written for a key, or `None` if the key should be left out.
+type Field = fn(&Device) -> Option<Value>;
+
+fn edit_device(devices: &mut dyn TableLike, local_name: &str, old: Option<&Device>, new: Option<&Device>) {
+    let new = match new {
+        Some(new) => new,
+        None => {
+            devices.remove(local_name);
+            return;
+        }
+    };
+    // the device might not have come from the file, in which case all of its values have to be written
+    let old = old.filter(|_| devices.get(local_name).is_some_and(Item::is_table_like));
+    if !devices.get(local_name).is_some_and(Item::is_table_like) {
+        devices.insert(local_name, Item::Table(Table::new()));
+    }
+    // written as a table or as an inline table, either of which is kept
+    let table = devices.get_mut(local_name).and_then(Item::as_table_like_mut).expect("device must be a table");
+    let fields: [(&str, Field); 11] = [
+        ("friendly_name", |device| Some(device.friendly_name.as_str().into())),
+        ("model", |device| (device.model != Model::default()).then(|| format!("{:?}", device.model).into())),
+        ("room", |device| device.room.as_deref().map(Value::from)),
+        ("floor", |device| device.floor.map(|floor| Value::from(floor as i64))),
+        ("location", |device| device.location.map(|location| Value::from(location.to_string()))),
+        ("tags", |device| (!device.tags.is_empty()).then(|| Value::Array(device.tags.iter().collect::<Array>()))),
+        ("notes", |device| device.notes.as_deref().map(Value::from)),
+        ("calibration", |device| calibration_to_value(device.calibration)),
+        ("stale_after_secs", |device| device.stale_after_secs.map(|secs| Value::from(secs as i64))),
+        ("temperature", |device| device.temperature.as_ref().map(|expression| Value::from(expression.to_string()))),
+        ("humidity", |device| device.humidity.as_ref().map(|expression| Value::from(expression.to_string()))),
+    ];
+    for (key, field) in fields {
+        let value = field(new);
+        // only touch the values that actually changed, to keep their comments and formatting
+        let to_string = |value: Option<Value>| value.map(|value| value.to_string());
+        if old.is_some_and(|old| to_string(field(old)) == to_string(value.clone())) {
+            continue;
+        }
+        match value {
+            Some(value) => {
+                table.insert(key, Item::Value(value));
+            }
+            None => {
+                table.remove(key);
+            }
+        }
+    }
+    // inline tables can't hold comments, and the values added to them would be misaligned otherwise
+    if let Some(inline_table) = devices.get_mut(local_name).and_then(Item::as_inline_table_mut) {
+        inline_table.fmt();
+    }
+}
+
+fn calibration_to_value(calibration: Calibration) -> Option<Value> {
+    let mut table = InlineTable::new();
+    if calibration.temperature_offset_in_c != 0.0 {
+        table.insert("temperature_offset_in_c", to_float(calibration.temperature_offset_in_c));
+    }
+    if calibration.humidity_offset != 0.0 {
+        table.insert("humidity_offset", to_float(calibration.humidity_offset));
+    }
+    (!table.is_empty()).then_some(Value::InlineTable(table))
+}
+
+/// Converts through the shortest decimal representation, so that `0.3` isn't written as `0.30000001192092896`.
+fn to_float(value: f32) -> Value {
+    Value::from(value.to_string().parse::<f64>().unwrap_or(value as f64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEVICES: &str = r#"# sensors around the house
+
+[GVH5075_0001]
+friendly_name = "Living Room" # by the window
+room = "living room"
+
+# the old one
+[GVH5075_0002]
+friendly_name = "Freezer"
+stale_after_secs = 600
+"#;
+
+    fn device(friendly_name: &str) -> Device {
+        Device {
+            friendly_name: friendly_name.to_string(),
+            model: Model::H5075,
+            room: None,
+            floor: None,
+            location: None,
+            tags: vec![],
+            notes: None,
+            calibration: Calibration::default(),
+            stale_after_secs: None,
+            temperature: None,
+            humidity: None,
+        }
+    }
+
+    #[test]
+    fn test_edits_preserve_comments_and_order() {
+        let mut document: DocumentMut = DEVICES.parse().unwrap();
+        let old = Device { room: Some("living room".to_string()), ..device("Living Room") };
+        let new = Device {
+            tags: vec!["indoor".to_string()],
+            calibration: Calibration { temperature_offset_in_c: -0.3, humidity_offset: 0.0 },
+            ..old.clone()
+        };
+        edit_device(document.as_table_mut(), "GVH5075_0001", Some(&old), Some(&new));
+        edit_device(document.as_table_mut(), "GVH5075_0003", None, Some(&device("Garage")));
+        assert_eq!(document.to_string(), r#"# sensors around the house
+
+[GVH5075_0001]
+friendly_name = "Living Room" # by the window
+room = "living room"
+tags = ["indoor"]
+calibration = { temperature_offset_in_c = -0.3 }
+
+# the old one
+[GVH5075_0002]
+friendly_name = "Freezer"
+stale_after_secs = 600
+
+[GVH5075_0003]
+friendly_name = "Garage"
+"#);
+    }
+
+    #[test]
+    fn test_removing_values_and_devices() {
+        let mut document: DocumentMut = DEVICES.parse().unwrap();
+        let old = Device { stale_after_secs: Some(600), ..device("Freezer") };
+        edit_device(document.as_table_mut(), "GVH5075_0002", Some(&old), Some(&device("Chest Freezer")));
+        assert!(document.to_string().ends_with("[GVH5075_0002]\nfriendly_name = \"Chest Freezer\"\n"));
+        edit_device(document.as_table_mut(), "GVH5075_0002", Some(&device("Chest Freezer")), None);
+        assert_eq!(document.to_string(), r#"# sensors around the house
+
+[GVH5075_0001]
+friendly_name = "Living Room" # by the window
+room = "living room"
+"#);
+    }
+
+    #[test]
+    fn test_inline_devices_keep_their_other_values() {
+        let mut document: DocumentMut = "GVH5075_0001 = { friendly_name = \"Living Room\", room = \"living room\" }\n".parse().unwrap();
+        let old = Device { room: Some("living room".to_string()), ..device("Living Room") };
+        let new = Device { friendly_name: "Lounge".to_string(), ..old.clone() };
+        edit_device(document.as_table_mut(), "GVH5075_0001", Some(&old), Some(&new));
+        assert_eq!(document.to_string(), "GVH5075_0001 = { friendly_name = \"Lounge\", room = \"living room\" }\n");
+
+        let mut document: DocumentMut = "devices = { GVH5075_0001 = { friendly_name = \"Living Room\" } }\n".parse().unwrap();
+        let devices = document["devices"].as_table_like_mut().unwrap();
+        edit_device(devices, "GVH5075_0001", Some(&device("Living Room")), Some(&Device { stale_after_secs: Some(600), ..device("Living Room") }));
+        assert_eq!(document.to_string(), "devices = { GVH5075_0001 = { friendly_name = \"Living Room\", stale_after_secs = 600 } }\n");
+    }
+
+    #[test]
+    fn test_devices_are_saved_to_their_section() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("config.toml");
+        fs::write(&path, "[service]\naddress = \"0.0.0.0:50051\"\n").unwrap();
+        let source = DevicesSource::ConfigFile(path.clone());
+        let (mut devices_file, devices) = DevicesFile::read(source.clone(), true).unwrap();
+        assert!(devices.is_empty());
+        devices_file.save_device("GVH5075_0001", None, Some(&device("Living Room"))).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), r#"[service]
+address = "0.0.0.0:50051"
+
+[devices.GVH5075_0001]
+friendly_name = "Living Room"
+"#);
+        let (_, devices) = DevicesFile::read(source, true).unwrap();
+        assert_eq!(devices["GVH5075_0001"], device("Living Room"));
+    }
+}
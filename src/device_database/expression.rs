@@ -0,0 +1,389 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// A value reported by a device, that expressions can refer to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Quantity {
+    Temperature,
+    Humidity,
+    Battery,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Function {
+    Mean,
+    Min,
+    Max,
+    Abs,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Number(f32),
+    Input { local_name: String, quantity: Quantity },
+    Negate(Box<Node>),
+    Binary(Operator, Box<Node>, Box<Node>),
+    Call(Function, Vec<Node>),
+}
+
+/// Longer expressions are rejected, as every operator nests the tree (and its evaluation) one level deeper.
+const MAX_LENGTH: usize = 1000;
+/// How deeply parentheses, function calls and negations can be nested, which the parser recurses into.
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("{message} at position {position} of {expression:?}")]
+pub struct ExpressionError {
+    expression: String,
+    // in characters, counting from 1
+    position: usize,
+    message: String,
+}
+
+/// Computes a value of a virtual device from the latest values of other devices,
+/// e.g. `GVH5075_6A19.temperature - GVH5075_A1B2.temperature`.
+///
+/// Supports numbers, `+`, `-`, `*`, `/`, parentheses, and the `mean`, `min`, `max` and `abs` functions.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct Expression {
+    source: String,
+    root: Node,
+}
+
+impl PartialEq for Expression {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl Expression {
+    /// The devices the expression depends on, in the order they first appear in.
+    pub fn inputs(&self) -> Vec<&str> {
+        let mut inputs = vec![];
+        collect_inputs(&self.root, &mut inputs);
+        inputs
+    }
+
+    /// Evaluates the expression, or returns `None` if a value it needs is missing (or it divides by zero).
+    ///
+    /// `mean`, `min` and `max` skip the missing values among their arguments, as long as there is any left.
+    pub fn evaluate(&self, value: &impl Fn(&str, Quantity) -> Option<f32>) -> Option<f32> {
+        evaluate(&self.root, value).filter(|result| result.is_finite())
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl FromStr for Expression {
+    type Err = ExpressionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser { source: s, chars: s.chars().collect(), position: 0, depth: 0 };
+        if parser.chars.len() > MAX_LENGTH {
+            return Err(parser.error_at(MAX_LENGTH, &format!("expression longer than {} characters", MAX_LENGTH)));
+        }
+        let root = parser.expression()?;
+        parser.skip_whitespace();
+        if parser.position < parser.chars.len() {
+            return Err(parser.error("unexpected character"));
+        }
+        Ok(Expression { source: s.to_string(), root })
+    }
+}
+
+impl TryFrom<String> for Expression {
+    type Error = ExpressionError;
+
+    fn try_from(source: String) -> Result<Self, Self::Error> {
+        source.parse()
+    }
+}
+
+fn collect_inputs<'a>(node: &'a Node, inputs: &mut Vec<&'a str>) {
+    match node {
+        Node::Number(_) => {}
+        Node::Input { local_name, .. } => {
+            if !inputs.contains(&local_name.as_str()) {
+                inputs.push(local_name);
+            }
+        }
+        Node::Negate(operand) => collect_inputs(operand, inputs),
+        Node::Binary(_, left, right) => {
+            collect_inputs(left, inputs);
+            collect_inputs(right, inputs);
+        }
+        Node::Call(_, arguments) => arguments.iter().for_each(|argument| collect_inputs(argument, inputs)),
+    }
+}
+
+fn evaluate(node: &Node, value: &impl Fn(&str, Quantity) -> Option<f32>) -> Option<f32> {
+    match node {
+        Node::Number(number) => Some(*number),
+        Node::Input { local_name, quantity } => value(local_name, *quantity),
+        Node::Negate(operand) => evaluate(operand, value).map(|operand| -operand),
+        Node::Binary(operator, left, right) => {
+            let (left, right) = (evaluate(left, value)?, evaluate(right, value)?);
+            match operator {
+                Operator::Add => Some(left + right),
+                Operator::Subtract => Some(left - right),
+                Operator::Multiply => Some(left * right),
+                Operator::Divide => (right != 0.0).then(|| left / right),
+            }
+        }
+        Node::Call(function, arguments) => {
+            let values: Vec<f32> = arguments.iter().filter_map(|argument| evaluate(argument, value)).collect();
+            if values.is_empty() || (*function == Function::Abs && values.len() != arguments.len()) {
+                return None;
+            }
+            match function {
+                Function::Mean => Some(values.iter().sum::<f32>() / values.len() as f32),
+                Function::Min => values.into_iter().reduce(f32::min),
+                Function::Max => values.into_iter().reduce(f32::max),
+                Function::Abs => Some(values[0].abs()),
+            }
+        }
+    }
+}
+
+/// Recursive descent over the grammar:
+///
+/// ```text
+/// expression = term (("+" | "-") term)*
+/// term       = unary (("*" | "/") unary)*
+/// unary      = "-" unary | primary
+/// primary    = number | "(" expression ")" | function "(" expression ("," expression)* ")" | device "." quantity
+/// ```
+struct Parser<'a> {
+    source: &'a str,
+    chars: Vec<char>,
+    position: usize,
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn expression(&mut self) -> Result<Node, ExpressionError> {
+        let mut node = self.term()?;
+        loop {
+            let operator = match self.peek() {
+                Some('+') => Operator::Add,
+                Some('-') => Operator::Subtract,
+                _ => return Ok(node),
+            };
+            self.position += 1;
+            node = Node::Binary(operator, Box::new(node), Box::new(self.term()?));
+        }
+    }
+
+    fn term(&mut self) -> Result<Node, ExpressionError> {
+        let mut node = self.unary()?;
+        loop {
+            let operator = match self.peek() {
+                Some('*') => Operator::Multiply,
+                Some('/') => Operator::Divide,
+                _ => return Ok(node),
+            };
+            self.position += 1;
+            node = Node::Binary(operator, Box::new(node), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Node, ExpressionError> {
+        if self.peek() == Some('-') {
+            self.position += 1;
+            return Ok(Node::Negate(Box::new(self.nested(Self::unary)?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Node, ExpressionError> {
+        match self.peek() {
+            Some('(') => {
+                self.position += 1;
+                let node = self.nested(Self::expression)?;
+                self.expect(')')?;
+                Ok(node)
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => self.number(),
+            Some(c) if c.is_alphabetic() || c == '_' => {
+                let start = self.position;
+                let name = self.identifier();
+                match self.peek() {
+                    Some('(') => self.call(start, &name),
+                    Some('.') => {
+                        self.position += 1;
+                        let quantity = self.quantity()?;
+                        Ok(Node::Input { local_name: name, quantity })
+                    }
+                    _ => Err(self.error_at(start, "expected a function call or a device value")),
+                }
+            }
+            Some(_) => Err(self.error("unexpected character")),
+            None => Err(self.error("unexpected end of expression")),
+        }
+    }
+
+    /// Parses one level deeper, unless that's too deep already.
+    fn nested(&mut self, parse: fn(&mut Self) -> Result<Node, ExpressionError>) -> Result<Node, ExpressionError> {
+        if self.depth == MAX_DEPTH {
+            return Err(self.error(&format!("nested more than {} levels deep", MAX_DEPTH)));
+        }
+        self.depth += 1;
+        let node = parse(self);
+        self.depth -= 1;
+        node
+    }
+
+    fn number(&mut self) -> Result<Node, ExpressionError> {
+        let start = self.position;
+        while self.chars.get(self.position).is_some_and(|c| c.is_ascii_digit() || *c == '.') {
+            self.position += 1;
+        }
+        let number: String = self.chars[start..self.position].iter().collect();
+        number.parse().map(Node::Number).map_err(|_| self.error_at(start, "invalid number"))
+    }
+
+    fn call(&mut self, start: usize, name: &str) -> Result<Node, ExpressionError> {
+        let function = match name {
+            "mean" => Function::Mean,
+            "min" => Function::Min,
+            "max" => Function::Max,
+            "abs" => Function::Abs,
+            _ => return Err(self.error_at(start, "unknown function")),
+        };
+        self.expect('(')?;
+        let mut arguments = vec![self.nested(Self::expression)?];
+        while self.peek() == Some(',') {
+            self.position += 1;
+            arguments.push(self.nested(Self::expression)?);
+        }
+        self.expect(')')?;
+        if function == Function::Abs && arguments.len() != 1 {
+            return Err(self.error_at(start, "abs takes a single argument"));
+        }
+        Ok(Node::Call(function, arguments))
+    }
+
+    fn quantity(&mut self) -> Result<Quantity, ExpressionError> {
+        let start = self.position;
+        match self.identifier().as_str() {
+            "temperature" => Ok(Quantity::Temperature),
+            "humidity" => Ok(Quantity::Humidity),
+            "battery" => Ok(Quantity::Battery),
+            _ => Err(self.error_at(start, "expected temperature, humidity or battery")),
+        }
+    }
+
+    fn identifier(&mut self) -> String {
+        let start = self.position;
+        while self.chars.get(self.position).is_some_and(|c| c.is_alphanumeric() || *c == '_' || *c == ':') {
+            self.position += 1;
+        }
+        self.chars[start..self.position].iter().collect()
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), ExpressionError> {
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.position += 1;
+                Ok(())
+            }
+            _ => Err(self.error(&format!("expected {:?}", expected))),
+        }
+    }
+
+    /// Skips the whitespace, and returns the next character without consuming it.
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.chars.get(self.position).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.chars.get(self.position).is_some_and(|c| c.is_whitespace()) {
+            self.position += 1;
+        }
+    }
+
+    fn error(&self, message: &str) -> ExpressionError {
+        self.error_at(self.position, message)
+    }
+
+    fn error_at(&self, position: usize, message: &str) -> ExpressionError {
+        ExpressionError { expression: self.source.to_string(), position: position + 1, message: message.to_string() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(local_name: &str, quantity: Quantity) -> Option<f32> {
+        match (local_name, quantity) {
+            ("living_room", Quantity::Temperature) => Some(21.5),
+            ("living_room", Quantity::Humidity) => Some(40.0),
+            ("outdoor", Quantity::Temperature) => Some(-3.5),
+            _ => None,
+        }
+    }
+
+    fn evaluate(source: &str) -> Option<f32> {
+        source.parse::<Expression>().unwrap().evaluate(&value)
+    }
+
+    #[test]
+    fn test_expressions_are_evaluated() {
+        assert_eq!(evaluate("living_room.temperature - outdoor.temperature"), Some(25.0));
+        assert_eq!(evaluate("-outdoor.temperature * 2 + 1"), Some(8.0));
+        assert_eq!(evaluate("(living_room.temperature - 1.5) / 4"), Some(5.0));
+        assert_eq!(evaluate("abs(outdoor.temperature)"), Some(3.5));
+        assert_eq!(evaluate("mean(living_room.temperature, outdoor.temperature, attic.temperature)"), Some(9.0));
+        assert_eq!(evaluate("max(living_room.humidity, attic.humidity)"), Some(40.0));
+        // missing values
+        assert_eq!(evaluate("living_room.temperature - attic.temperature"), None);
+        assert_eq!(evaluate("min(attic.temperature, attic.humidity)"), None);
+        assert_eq!(evaluate("living_room.temperature / 0"), None);
+    }
+
+    #[test]
+    fn test_inputs_are_listed_once() {
+        let expression: Expression = "mean(b.temperature, a.temperature) - b.humidity".parse().unwrap();
+        assert_eq!(expression.inputs(), vec!["b", "a"]);
+    }
+
+    #[test]
+    fn test_invalid_expressions_are_rejected() {
+        let error = |source: &str| source.parse::<Expression>().unwrap_err().to_string();
+        assert_eq!(error("living_room.temperature -"), "unexpected end of expression at position 26 of \"living_room.temperature -\"");
+        assert_eq!(error("living_room.pressure"), "expected temperature, humidity or battery at position 13 of \"living_room.pressure\"");
+        assert_eq!(error("median(a.temperature)"), "unknown function at position 1 of \"median(a.temperature)\"");
+        assert_eq!(error("abs(a.temperature, b.temperature)"), "abs takes a single argument at position 1 of \"abs(a.temperature, b.temperature)\"");
+        assert_eq!(error("living_room"), "expected a function call or a device value at position 1 of \"living_room\"");
+        assert_eq!(error("(1 + 2"), "expected ')' at position 7 of \"(1 + 2\"");
+        assert_eq!(error("1 2"), "unexpected character at position 3 of \"1 2\"");
+    }
+
+    #[test]
+    fn test_deep_and_long_expressions_are_rejected() {
+        let error = |source: &str| source.parse::<Expression>().unwrap_err().message;
+        let nested = |depth: usize| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+        assert_eq!(evaluate(&nested(MAX_DEPTH)), Some(1.0));
+        assert_eq!(error(&nested(MAX_DEPTH + 1)), "nested more than 32 levels deep");
+        assert_eq!(error(&"-".repeat(100_000)), "expression longer than 1000 characters");
+        assert_eq!(error(&format!("{}1", "abs(".repeat(MAX_DEPTH + 1))), "nested more than 32 levels deep");
+        // a long sum isn't nested in the source, but it is in the tree
+        assert!(vec!["a.temperature"; 50].join(" + ").parse::<Expression>().is_ok());
+        assert_eq!(error(&vec!["a.temperature"; 100].join(" + ")), "expression longer than 1000 characters");
+    }
+}
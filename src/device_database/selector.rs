@@ -0,0 +1,59 @@
+use std::str::FromStr;
+
+use super::{Device, DeviceDatabaseError, Location};
+
+/// Selects devices by their metadata rather than by their ids, e.g. `room=basement` or `tag=freezer`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Selector {
+    Room(String),
+    Floor(i32),
+    Location(Location),
+    Tag(String),
+}
+
+impl Selector {
+    pub fn matches(&self, device: &Device) -> bool {
+        match self {
+            Selector::Room(room) => device.room.as_ref() == Some(room),
+            Selector::Floor(floor) => device.floor == Some(*floor),
+            Selector::Location(location) => device.location == Some(*location),
+            Selector::Tag(tag) => device.tags.contains(tag),
+        }
+    }
+}
+
+impl FromStr for Selector {
+    type Err = DeviceDatabaseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || DeviceDatabaseError::InvalidSelector(s.to_string());
+        let (key, value) = s.split_once('=').ok_or_else(invalid)?;
+        let value = value.trim();
+        if value.is_empty() {
+            return Err(invalid());
+        }
+        match key.trim() {
+            "room" => Ok(Selector::Room(value.to_string())),
+            "floor" => value.parse().map(Selector::Floor).map_err(|_| invalid()),
+            "location" => value.parse().map(Selector::Location).map_err(|_| invalid()),
+            "tag" => Ok(Selector::Tag(value.to_string())),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_selectors_are_parsed() {
+        assert_eq!("room=basement".parse::<Selector>().unwrap(), Selector::Room("basement".to_string()));
+        assert_eq!("floor=-1".parse::<Selector>().unwrap(), Selector::Floor(-1));
+        assert_eq!("location=outdoor".parse::<Selector>().unwrap(), Selector::Location(Location::Outdoor));
+        assert_eq!("tag = freezer".parse::<Selector>().unwrap(), Selector::Tag("freezer".to_string()));
+        for invalid in ["basement", "room=", "floor=first", "location=attic", "colour=blue"] {
+            assert!(matches!(invalid.parse::<Selector>(), Err(DeviceDatabaseError::InvalidSelector(_))), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+
+use serde::Deserialize;
+use toml_edit::de::Deserializer;
+use toml_edit::{DocumentMut, ImDocument, Item, TableLike};
+
+use super::{check_expressions, Device, DEVICES_SECTION};
+
+/// A problem found in the devices file, along with where it is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl Diagnostic {
+    /// Points at the start of the span (or at the start of the file, if there is none), counting from 1.
+    fn at(file_contents: &str, span: Option<Range<usize>>, message: impl Into<String>) -> Diagnostic {
+        let offset = span.map_or(0, |span| span.start);
+        let before = file_contents.get(..offset).unwrap_or(file_contents);
+        Diagnostic {
+            line: before.matches('\n').count() + 1,
+            column: before.rsplit('\n').next().unwrap_or_default().chars().count() + 1,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, column {}: {}", self.line, self.column, self.message)
+    }
+}
+
+/// The devices described by the file, and the problems that don't keep them from being used.
+#[derive(Debug)]
+pub struct ParsedDevices {
+    pub document: DocumentMut,
+    pub devices: HashMap<String, Device>,
+    pub problems: Vec<Diagnostic>,
+}
+
+/// The devices section of a service configuration file, whose other sections are checked elsewhere.
+#[derive(Deserialize)]
+struct DevicesSection {
+    #[serde(default)]
+    devices: HashMap<String, Device>,
+}
+
+/// Fails on anything that makes the file unusable (syntax errors, missing values or values of the wrong type),
+/// and reports unknown keys, empty friendly names, friendly names used by several devices
+/// and virtual devices that can't be computed as problems.
+///
+/// The devices are either at the top level of the file, or in its devices section.
+pub fn parse_devices(file_contents: &str, sectioned: bool) -> Result<ParsedDevices, Diagnostic> {
+    let document = ImDocument::parse(file_contents)
+        .map_err(|err| Diagnostic::at(file_contents, err.span(), err.message()))?;
+    let mut unknown_keys = vec![];
+    let deserializer = Deserializer::from(document.clone());
+    let on_unknown_key = |path: serde_ignored::Path| unknown_keys.push(to_keys(&path));
+    let devices = match sectioned {
+        true => serde_ignored::deserialize(deserializer, on_unknown_key).map(|section: DevicesSection| section.devices),
+        false => serde_ignored::deserialize(deserializer, on_unknown_key),
+    }.map_err(|err| Diagnostic::at(file_contents, err.span(), err.message()))?;
+    let empty = toml_edit::Table::new();
+    let table: &dyn TableLike = match sectioned {
+        true => document.get(DEVICES_SECTION).and_then(Item::as_table_like).unwrap_or(&empty),
+        false => document.as_table(),
+    };
+
+    let mut problems = vec![];
+    // the other sections are none of our business
+    let unknown_keys = unknown_keys.into_iter().filter(|keys| !sectioned || keys.len() > 1);
+    for keys in unknown_keys {
+        let span = locate_key(document.as_table(), &keys);
+        let (key, table) = keys.split_last().expect("unknown keys are never at the root");
+        let message = match table.is_empty() {
+            true => format!("unknown key {:?}", key),
+            false => format!("unknown key {:?} in {}", key, table.join(".")),
+        };
+        problems.push(Diagnostic::at(file_contents, span, message));
+    }
+    // in the order of the file, so that the second use of a friendly name is the one reported
+    let mut friendly_names: HashMap<&str, &str> = HashMap::new();
+    for (local_name, item) in table.iter() {
+        let device = &devices[local_name];
+        let friendly_name = device.friendly_name.trim();
+        if let Err(message) = check_expressions(local_name, device, &devices) {
+            let span = table.key(local_name).and_then(|key| key.span());
+            problems.push(Diagnostic::at(file_contents, span, message));
+        }
+        let span = item.as_table_like().and_then(|table| table.get("friendly_name")).and_then(Item::span);
+        if friendly_name.is_empty() {
+            problems.push(Diagnostic::at(file_contents, span, format!("empty friendly name for {}", local_name)));
+        } else {
+            match friendly_names.entry(friendly_name) {
+                Entry::Occupied(other) => {
+                    let message = format!("friendly name {:?} of {} is already used by {}",
+                        friendly_name, local_name, other.get());
+                    problems.push(Diagnostic::at(file_contents, span, message));
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(local_name);
+                }
+            }
+        }
+    }
+    problems.sort_by_key(|problem| (problem.line, problem.column));
+    Ok(ParsedDevices { document: document.into_mut(), devices, problems })
+}
+
+fn to_keys(path: &serde_ignored::Path) -> Vec<String> {
+    use serde_ignored::Path;
+
+    match path {
+        Path::Root => vec![],
+        Path::Map { parent, key } => {
+            let mut keys = to_keys(parent);
+            keys.push(key.clone());
+            keys
+        }
+        Path::Seq { parent, index } => {
+            let mut keys = to_keys(parent);
+            keys.push(index.to_string());
+            keys
+        }
+        Path::Some { parent } | Path::NewtypeStruct { parent } | Path::NewtypeVariant { parent } => to_keys(parent),
+    }
+}
+
+fn locate_key(table: &dyn TableLike, keys: &[String]) -> Option<Range<usize>> {
+    match keys {
+        [] => None,
+        [key] => table.key(key).and_then(|key| key.span()),
+        [key, rest @ ..] => locate_key(table.get(key)?.as_table_like()?, rest),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn problems(file_contents: &str) -> Vec<String> {
+        parse_devices(file_contents, false).unwrap().problems.iter().map(Diagnostic::to_string).collect()
+    }
+
+    #[test]
+    fn test_problems_point_at_their_location() {
+        let file_contents = r#"[GVH5075_0001]
+friendly_name = "Living Room"
+colour = "blue"
+
+[GVH5075_0002]
+friendly_name = "  "
+calibration = { temperature_offset = -0.5 }
+
+[GVH5075_0003]
+friendly_name = "Living Room"
+"#;
+        assert_eq!(problems(file_contents), vec![
+            "line 3, column 1: unknown key \"colour\" in GVH5075_0001",
+            "line 6, column 17: empty friendly name for GVH5075_0002",
+            "line 7, column 17: unknown key \"temperature_offset\" in GVH5075_0002.calibration",
+            "line 10, column 17: friendly name \"Living Room\" of GVH5075_0003 is already used by GVH5075_0001",
+        ]);
+        assert!(problems("[GVH5075_0001]\nfriendly_name = \"Living Room\"\n").is_empty());
+    }
+
+    #[test]
+    fn test_virtual_devices_are_checked() {
+        let file_contents = r#"[GVH5075_0001]
+friendly_name = "Living Room"
+
+[delta]
+friendly_name = "Delta"
+model = "Virtual"
+temperature = "GVH5075_0001.temperature - outdoor.temperature"
+
+[GVH5075_0002]
+friendly_name = "Garage"
+humidity = "GVH5075_0001.humidity"
+"#;
+        assert_eq!(problems(file_contents), vec![
+            "line 4, column 2: virtual device delta refers to unknown device outdoor",
+            "line 9, column 2: GVH5075_0002 has expressions, but only virtual devices can have them",
+        ]);
+        let error = parse_devices("[delta]\nfriendly_name = \"Delta\"\nmodel = \"Virtual\"\ntemperature = \"a.temperature -\"\n", false);
+        assert_eq!(error.unwrap_err().line, 4);
+    }
+
+    #[test]
+    fn test_devices_section_is_checked() {
+        let file_contents = r#"[service]
+address = "0.0.0.0:50051"
+
+[devices.GVH5075_0001]
+friendly_name = "Living Room"
+colour = "blue"
+"#;
+        let parsed = parse_devices(file_contents, true).unwrap();
+        assert_eq!(parsed.devices.keys().collect::<Vec<_>>(), vec!["GVH5075_0001"]);
+        assert_eq!(parsed.problems.iter().map(Diagnostic::to_string).collect::<Vec<_>>(), vec![
+            "line 6, column 1: unknown key \"colour\" in devices.GVH5075_0001",
+        ]);
+        assert!(parse_devices("[service]\n", true).unwrap().devices.is_empty());
+    }
+
+    #[test]
+    fn test_unusable_files_are_rejected() {
+        let error = parse_devices("[GVH5075_0001]\nfriendly_name = \n", false).unwrap_err();
+        assert_eq!((error.line, error.column), (2, 17));
+        let error = parse_devices("[GVH5075_0001]\nfriendly_name = \"Living Room\"\nstale_after_secs = \"soon\"\n", false).unwrap_err();
+        assert_eq!(error.line, 3);
+    }
+}
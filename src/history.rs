@@ -0,0 +1,309 @@
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+
+use rusqlite::{params, Connection, OpenFlags};
+use serde::Deserialize;
+use tokio::task::spawn_blocking;
+use tokio::time::interval;
+
+use crate::collector::{Reading, ReadingSubscriber};
+pub use query::{Bucket, HistoryQuery, Summary};
+
+mod compaction;
+mod query;
+
+/// How often the readings received in the meantime are written to the database.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How many readings are written at most in a single transaction.
+const MAX_BATCH_SIZE: usize = 512;
+
+/// Every change to the schema, in the order they are applied in.
+///
+/// The database remembers how many of them it has seen (as its `user_version`), so they must never be edited,
+/// only appended to.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE readings (
+        device TEXT NOT NULL,
+        timestamp_ms INTEGER NOT NULL,
+        temperature_in_c REAL,
+        humidity REAL,
+        battery INTEGER
+    );
+    CREATE INDEX readings_by_device_and_time ON readings (device, timestamp_ms);",
+    "CREATE TABLE rollups (
+        device TEXT NOT NULL,
+        resolution_secs INTEGER NOT NULL,
+        timestamp_ms INTEGER NOT NULL,
+        count INTEGER NOT NULL,
+        temperature_in_c_count INTEGER,
+        temperature_in_c_min REAL,
+        temperature_in_c_max REAL,
+        temperature_in_c_sum REAL,
+        temperature_in_c_last REAL,
+        humidity_count INTEGER,
+        humidity_min REAL,
+        humidity_max REAL,
+        humidity_sum REAL,
+        humidity_last REAL,
+        battery_count INTEGER,
+        battery_min REAL,
+        battery_max REAL,
+        battery_sum REAL,
+        battery_last REAL,
+        PRIMARY KEY (resolution_secs, device, timestamp_ms)
+    );
+    -- expired readings are removed regardless of the device
+    CREATE INDEX readings_by_time ON readings (timestamp_ms);
+    CREATE TABLE compaction (
+        resolution_secs INTEGER PRIMARY KEY,
+        rolled_up_until_ms INTEGER NOT NULL
+    );",
+];
+
+/// How long the readings are kept, and at what resolution.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tier {
+    /// The size of the buckets the readings are rolled up into, or none for the readings themselves.
+    pub resolution_secs: Option<u64>,
+    /// Kept forever, if none.
+    pub retention_days: Option<u64>,
+}
+
+impl Tier {
+    fn resolution_ms(&self) -> Option<i64> {
+        self.resolution_secs.map(|secs| secs as i64 * 1000)
+    }
+
+    fn retention_ms(&self) -> Option<i64> {
+        self.retention_days.map(|days| days as i64 * 24 * 60 * 60 * 1000)
+    }
+}
+
+/// The readings themselves for a week, 5-minute rollups for 90 days and hourly rollups forever.
+pub fn default_tiers() -> Vec<Tier> {
+    vec![
+        Tier { resolution_secs: None, retention_days: Some(7) },
+        Tier { resolution_secs: Some(5 * 60), retention_days: Some(90) },
+        Tier { resolution_secs: Some(60 * 60), retention_days: None },
+    ]
+}
+
+/// The first tier has to keep the readings themselves, and every following one is rolled up from the one before it,
+/// so its resolution has to be a multiple of that one's.
+pub fn check_tiers(tiers: &[Tier]) -> Result<(), String> {
+    match tiers.first() {
+        None => return Err("there has to be at least one history tier".to_string()),
+        Some(tier) if tier.resolution_secs.is_some() => {
+            return Err("the first history tier has to keep the readings themselves, without a resolution".to_string());
+        }
+        Some(_) => {}
+    }
+    if tiers.iter().any(|tier| tier.retention_days == Some(0)) {
+        return Err("history tiers have to be kept for at least a day".to_string());
+    }
+    let mut previous = 1;
+    for tier in &tiers[1..] {
+        match tier.resolution_secs {
+            Some(resolution) if resolution > previous && resolution % previous == 0 => previous = resolution,
+            _ => return Err(format!(
+                "the resolution of every history tier has to be a multiple of the previous one ({} seconds)", previous
+            )),
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum HistoryError {
+    #[error("unable to create the directory of the history database: {0}")]
+    DirectoryNotCreated(#[from] io::Error),
+    #[error("{0}")]
+    Database(#[from] rusqlite::Error),
+    #[error("the history database is at version {0}, which is newer than this version of the service supports")]
+    UnsupportedVersion(usize),
+}
+
+/// Every reading the collector received, kept in an embedded SQLite database.
+pub struct History {
+    // shared with the blocking tasks the writes are made on
+    connection: Arc<Mutex<Connection>>,
+    // queries have their own connection, so that they don't hold up the writes
+    reader: Arc<Mutex<Connection>>,
+    // checked by `check_tiers`
+    tiers: Arc<Vec<Tier>>,
+}
+
+impl History {
+    /// Opens the database (creating it if needed), and brings its schema up to date.
+    pub fn open(path: &Path, tiers: Vec<Tier>) -> Result<History, HistoryError> {
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory)?;
+        }
+        let connection = Connection::open(path)?;
+        // lets readers carry on while the readings are written
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.pragma_update(None, "synchronous", "NORMAL")?;
+        let connection = History::migrated(connection)?;
+        let reader = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        Ok(History { connection, reader: Arc::new(Mutex::new(reader)), tiers: Arc::new(tiers) })
+    }
+
+    #[cfg(test)]
+    pub fn open_in_memory(tiers: Vec<Tier>) -> History {
+        let connection = History::migrated(Connection::open_in_memory().unwrap()).unwrap();
+        History { reader: Arc::clone(&connection), connection, tiers: Arc::new(tiers) }
+    }
+
+    fn migrated(mut connection: Connection) -> Result<Arc<Mutex<Connection>>, HistoryError> {
+        migrate(&mut connection)?;
+        Ok(Arc::new(Mutex::new(connection)))
+    }
+
+    /// Writes the readings the collector receives in batches, until it's gone or the service is stopping.
+    pub async fn record(&self, mut readings: ReadingSubscriber, stopping: impl Future<Output = ()>) {
+        let mut batch = vec![];
+        let mut ticks = interval(FLUSH_INTERVAL);
+        tokio::pin!(stopping);
+        loop {
+            tokio::select! {
+                _ = &mut stopping => break,
+                reading = readings.recv() => match reading {
+                    Some(reading) => {
+                        batch.push(reading);
+                        if batch.len() >= MAX_BATCH_SIZE {
+                            self.flush(&mut batch).await;
+                        }
+                    }
+                    None => break,
+                },
+                _ = ticks.tick() => self.flush(&mut batch).await,
+            }
+        }
+        self.flush(&mut batch).await;
+    }
+
+    async fn flush(&self, batch: &mut Vec<Reading>) {
+        if batch.is_empty() {
+            return;
+        }
+        let readings = std::mem::take(batch);
+        let connection = Arc::clone(&self.connection);
+        let result = spawn_blocking(move || {
+            let mut connection = connection.lock().expect("Could not lock mutex");
+            insert(&mut connection, &readings).map(|()| readings.len())
+        }).await.expect("Writing the history panicked");
+        match result {
+            Ok(count) => trace!("Wrote {} readings to the history", count),
+            // the readings are lost, but the following ones might still make it
+            Err(err) => error!("Unable to write the history: {}", err),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn insert(&self, readings: &[Reading]) {
+        insert(&mut self.connection.lock().expect("Could not lock mutex"), readings).unwrap();
+    }
+}
+
+fn migrate(connection: &mut Connection) -> Result<(), HistoryError> {
+    let version: usize = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        return Err(HistoryError::UnsupportedVersion(version));
+    }
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        info!("Migrating the history database to version {}", index + 1);
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", index + 1)?;
+        transaction.commit()?;
+    }
+    Ok(())
+}
+
+fn insert(connection: &mut Connection, readings: &[Reading]) -> rusqlite::Result<()> {
+    let transaction = connection.transaction()?;
+    {
+        let mut statement = transaction.prepare_cached(
+            "INSERT INTO readings (device, timestamp_ms, temperature_in_c, humidity, battery) VALUES (?, ?, ?, ?, ?)",
+        )?;
+        for reading in readings {
+            let timestamp_ms = reading.data.last_update_timestamp().duration_since(UNIX_EPOCH)
+                .map_or(0, |timestamp| timestamp.as_millis() as i64);
+            statement.execute(params![
+                reading.local_name,
+                timestamp_ms,
+                reading.data.temperature_in_c(),
+                reading.data.humidity(),
+                reading.data.battery(),
+            ])?;
+        }
+    }
+    transaction.commit()
+}
+
+#[cfg(test)]
+fn insert_reading(history: &History, device: &str, timestamp_ms: i64, temperature_in_c: f32, humidity: Option<f32>) {
+    let connection = history.connection.lock().unwrap();
+    connection.execute(
+        "INSERT INTO readings (device, timestamp_ms, temperature_in_c, humidity, battery) VALUES (?, ?, ?, ?, 100)",
+        params![device, timestamp_ms, temperature_in_c, humidity],
+    ).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn count(history: &History, device: &str) -> i64 {
+        let connection = history.connection.lock().unwrap();
+        connection.query_row("SELECT COUNT(*) FROM readings WHERE device = ?", [device], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn test_readings_are_inserted() {
+        let history = History::open_in_memory(default_tiers());
+        history.insert(&[
+            Reading::h5075("GVH5075_0001", 21.5, 40.0, 100),
+            Reading::h5075("GVH5075_0001", 21.6, 40.5, 100),
+            Reading::h5075("GVH5075_0002", -18.0, 70.0, 90),
+        ]);
+        assert_eq!(count(&history, "GVH5075_0001"), 2);
+        assert_eq!(count(&history, "GVH5075_0002"), 1);
+    }
+
+    #[test]
+    fn test_tiers_are_checked() {
+        assert_eq!(check_tiers(&default_tiers()), Ok(()));
+        let raw = Tier { resolution_secs: None, retention_days: Some(7) };
+        let rollup = |resolution_secs| Tier { resolution_secs: Some(resolution_secs), retention_days: None };
+        assert_eq!(check_tiers(std::slice::from_ref(&raw)), Ok(()));
+        assert!(check_tiers(&[]).is_err());
+        assert!(check_tiers(&[rollup(60)]).is_err());
+        assert!(check_tiers(&[raw.clone(), rollup(300), rollup(450)]).is_err());
+        assert!(check_tiers(&[raw.clone(), rollup(300), rollup(300)]).is_err());
+        assert!(check_tiers(&[Tier { retention_days: Some(0), ..raw }]).is_err());
+    }
+
+    #[test]
+    fn test_migrations_are_applied_once() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("history.sqlite3");
+        let history = History::open(&path, default_tiers()).unwrap();
+        history.insert(&[Reading::h5075("GVH5075_0001", 21.5, 40.0, 100)]);
+        drop(history);
+        let history = History::open(&path, default_tiers()).unwrap();
+        assert_eq!(count(&history, "GVH5075_0001"), 1);
+        drop(history);
+
+        let connection = Connection::open(&path).unwrap();
+        connection.pragma_update(None, "user_version", MIGRATIONS.len() + 1).unwrap();
+        drop(connection);
+        assert!(matches!(History::open(&path, default_tiers()), Err(HistoryError::UnsupportedVersion(_))));
+    }
+}
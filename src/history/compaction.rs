@@ -0,0 +1,299 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rusqlite::{params, Connection, OptionalExtension};
+use tokio::task::spawn_blocking;
+use tokio::time::interval;
+
+use super::query::{add_to_buckets, read_tier, rolled_up_until};
+use super::{Bucket, History, Summary, Tier};
+
+/// How often the readings are rolled up and expired.
+const COMPACTION_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// How long to wait for readings that are written late (as they are written in batches) before rolling them up.
+const LATENESS_MS: i64 = 60 * 1000;
+
+/// How much time is rolled up at once, each in a transaction of its own, so that the readings can be written in between.
+const SLICE_MS: i64 = 6 * 60 * 60 * 1000;
+
+/// How many expired rows are removed at once, for the same reason.
+const MAX_REMOVED_ROWS: usize = 10_000;
+
+impl History {
+    /// Periodically rolls the readings up into the coarser tiers, and removes the ones that are past their retention.
+    pub async fn compact_periodically(&self) {
+        let mut ticks = interval(COMPACTION_INTERVAL);
+        loop {
+            ticks.tick().await;
+            let connection = Arc::clone(&self.connection);
+            let tiers = Arc::clone(&self.tiers);
+            let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_millis() as i64);
+            let result = spawn_blocking(move || compact(&connection, &tiers, now_ms))
+                .await.expect("Compacting the history panicked");
+            if let Err(err) = result {
+                error!("Unable to compact the history: {}", err);
+            }
+        }
+    }
+}
+
+/// Rolls up and expires the readings a slice at a time, only holding the connection for one slice.
+fn compact(connection: &Mutex<Connection>, tiers: &[Tier], now_ms: i64) -> rusqlite::Result<()> {
+    let lock = || connection.lock().expect("Could not lock mutex");
+    // every tier is rolled up from the one before it, as far as that one is complete
+    let mut complete_until_ms = Some(now_ms - LATENESS_MS);
+    for pair in tiers.windows(2) {
+        let (source, target) = (&pair[0], &pair[1]);
+        let resolution_secs = target.resolution_secs.expect("only the first tier keeps the readings themselves");
+        let resolution_ms = resolution_secs as i64 * 1000;
+        let align = |timestamp_ms: i64| timestamp_ms.div_euclid(resolution_ms) * resolution_ms;
+        // released before the earliest row is looked up
+        let rolled_up_until_ms = rolled_up_until(&lock(), resolution_secs)?;
+        let from_ms = match rolled_up_until_ms {
+            Some(from_ms) => Some(from_ms),
+            // never rolled up before, so everything there is in the source tier is yet to be
+            None => earliest(&lock(), source, i64::MIN)?.map(align),
+        };
+        let until_ms = complete_until_ms.map(align);
+        if let (Some(mut from_ms), Some(until_ms)) = (from_ms, until_ms) {
+            // a whole number of buckets
+            let slice_ms = (SLICE_MS / resolution_ms).max(1) * resolution_ms;
+            let mut count = 0;
+            while from_ms < until_ms {
+                let mut connection = lock();
+                let transaction = connection.transaction()?;
+                // skips the time without anything to roll up
+                let next_ms = earliest(&transaction, source, from_ms)?.map_or(until_ms, align).clamp(from_ms, until_ms);
+                let slice_until_ms = (next_ms + slice_ms).min(until_ms);
+                if next_ms < slice_until_ms {
+                    count += roll_up(&transaction, source, resolution_secs, next_ms, slice_until_ms)?;
+                }
+                transaction.execute(
+                    "INSERT OR REPLACE INTO compaction (resolution_secs, rolled_up_until_ms) VALUES (?, ?)",
+                    params![resolution_secs, slice_until_ms],
+                )?;
+                transaction.commit()?;
+                from_ms = slice_until_ms;
+            }
+            if count > 0 {
+                debug!("Rolled up {} buckets of {} seconds", count, resolution_secs);
+            }
+        }
+        complete_until_ms = rolled_up_until(&lock(), resolution_secs)?;
+    }
+    for (index, tier) in tiers.iter().enumerate() {
+        let retention_ms = match tier.retention_ms() {
+            Some(retention_ms) => retention_ms,
+            None => continue,
+        };
+        // whatever hasn't been rolled up into the next tier yet has to stay
+        let rolled_up_until_ms = match tiers.get(index + 1).and_then(|next| next.resolution_secs) {
+            Some(resolution_secs) => rolled_up_until(&lock(), resolution_secs)?.unwrap_or(i64::MIN),
+            None => i64::MAX,
+        };
+        let expired_before_ms = (now_ms - retention_ms).min(rolled_up_until_ms);
+        let mut removed = 0;
+        loop {
+            let removed_now = match tier.resolution_secs {
+                None => lock().execute(
+                    "DELETE FROM readings WHERE rowid IN (SELECT rowid FROM readings WHERE timestamp_ms < ? LIMIT ?)",
+                    params![expired_before_ms, MAX_REMOVED_ROWS],
+                )?,
+                Some(resolution_secs) => lock().execute(
+                    "DELETE FROM rollups WHERE rowid IN (
+                        SELECT rowid FROM rollups WHERE resolution_secs = ? AND timestamp_ms < ? LIMIT ?
+                    )",
+                    params![resolution_secs, expired_before_ms, MAX_REMOVED_ROWS],
+                )?,
+            };
+            removed += removed_now;
+            if removed_now < MAX_REMOVED_ROWS {
+                break;
+            }
+        }
+        if removed > 0 {
+            debug!("Removed {} expired rows of history", removed);
+        }
+    }
+    Ok(())
+}
+
+/// The time of the earliest row of the tier at or after the given time.
+fn earliest(connection: &Connection, tier: &Tier, since_ms: i64) -> rusqlite::Result<Option<i64>> {
+    let earliest_ms: Option<i64> = match tier.resolution_secs {
+        None => connection.query_row(
+            "SELECT MIN(timestamp_ms) FROM readings WHERE timestamp_ms >= ?",
+            [since_ms],
+            |row| row.get(0),
+        ).optional()?,
+        Some(resolution_secs) => connection.query_row(
+            "SELECT MIN(timestamp_ms) FROM rollups WHERE resolution_secs = ? AND timestamp_ms >= ?",
+            params![resolution_secs, since_ms],
+            |row| row.get(0),
+        ).optional()?,
+    }.flatten();
+    Ok(earliest_ms)
+}
+
+/// Rolls the source tier up into buckets of the given resolution, and returns how many there were.
+fn roll_up(connection: &Connection, source: &Tier, resolution_secs: u64, from_ms: i64, until_ms: i64) -> rusqlite::Result<usize> {
+    let mut rollups: Vec<(String, Vec<Bucket>)> = vec![];
+    read_tier(connection, source, None, from_ms, until_ms, |device, partial| {
+        if rollups.last().map(|(last, _)| last) != Some(&device) {
+            rollups.push((device, vec![]));
+        }
+        let (_, buckets) = rollups.last_mut().expect("device was just added");
+        // aligned to the epoch, so that every tier's buckets fit into the next one's
+        add_to_buckets(buckets, partial, 0, resolution_secs as i64 * 1000);
+    })?;
+    let mut statement = connection.prepare_cached(
+        "INSERT OR REPLACE INTO rollups (
+            device, resolution_secs, timestamp_ms, count,
+            temperature_in_c_count, temperature_in_c_min, temperature_in_c_max, temperature_in_c_sum, temperature_in_c_last,
+            humidity_count, humidity_min, humidity_max, humidity_sum, humidity_last,
+            battery_count, battery_min, battery_max, battery_sum, battery_last
+        ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+    )?;
+    let mut count = 0;
+    for (device, buckets) in &rollups {
+        for bucket in buckets {
+            let (temperature, humidity, battery) =
+                (summary_columns(bucket.temperature_in_c), summary_columns(bucket.humidity), summary_columns(bucket.battery));
+            statement.execute(params![
+                device, resolution_secs, bucket.start_ms, bucket.count,
+                temperature.0, temperature.1, temperature.2, temperature.3, temperature.4,
+                humidity.0, humidity.1, humidity.2, humidity.3, humidity.4,
+                battery.0, battery.1, battery.2, battery.3, battery.4,
+            ])?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+type SummaryColumns = (Option<u64>, Option<f32>, Option<f32>, Option<f64>, Option<f32>);
+
+fn summary_columns(summary: Option<Summary>) -> SummaryColumns {
+    match summary {
+        Some(summary) => (Some(summary.count), Some(summary.min), Some(summary.max), Some(summary.sum), Some(summary.last)),
+        None => (None, None, None, None, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{default_tiers, HistoryQuery, insert_reading};
+
+    const MINUTE_MS: i64 = 60 * 1000;
+    const DAY_MS: i64 = 24 * 60 * MINUTE_MS;
+
+    fn compact_at(history: &History, now_ms: i64) {
+        compact(&history.connection, &history.tiers, now_ms).unwrap();
+    }
+
+    fn count(history: &History, table: &str) -> i64 {
+        let connection = history.connection.lock().unwrap();
+        connection.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn test_readings_are_rolled_up_and_expired() {
+        let history = History::open_in_memory(default_tiers());
+        // a reading every minute for 2 hours
+        for minute in 0..120 {
+            insert_reading(&history, "GVH5075_0001", minute * MINUTE_MS, minute as f32, None);
+        }
+        compact_at(&history, 2 * 60 * MINUTE_MS);
+        // the last 5 minutes might still get late readings
+        assert_eq!(count(&history, "rollups WHERE resolution_secs = 300"), 23);
+        assert_eq!(count(&history, "rollups WHERE resolution_secs = 3600"), 1);
+        assert_eq!(count(&history, "readings"), 120);
+
+        // rolling up again doesn't change anything
+        compact_at(&history, 2 * 60 * MINUTE_MS);
+        assert_eq!(count(&history, "rollups"), 24);
+
+        compact_at(&history, 8 * DAY_MS);
+        assert_eq!(count(&history, "readings"), 0);
+        assert_eq!(count(&history, "rollups WHERE resolution_secs = 300"), 24);
+        assert_eq!(count(&history, "rollups WHERE resolution_secs = 3600"), 2);
+        compact_at(&history, 100 * DAY_MS);
+        assert_eq!(count(&history, "rollups WHERE resolution_secs = 300"), 0);
+        assert_eq!(count(&history, "rollups WHERE resolution_secs = 3600"), 2);
+    }
+
+    #[test]
+    fn test_long_and_sparse_histories_are_rolled_up_in_slices() {
+        let history = History::open_in_memory(default_tiers());
+        // every 10 minutes for 2 days, then once more a month later
+        for minute in (0..2 * 24 * 60).step_by(10) {
+            insert_reading(&history, "GVH5075_0001", minute * MINUTE_MS, 20.0, None);
+        }
+        insert_reading(&history, "GVH5075_0001", 32 * DAY_MS, 20.0, None);
+        compact_at(&history, 33 * DAY_MS);
+        assert_eq!(count(&history, "rollups WHERE resolution_secs = 300"), 2 * 24 * 6 + 1);
+        assert_eq!(count(&history, "rollups WHERE resolution_secs = 3600"), 2 * 24 + 1);
+        assert_eq!(count(&history, "readings"), 1);
+        let connection = history.connection.lock().unwrap();
+        assert_eq!(rolled_up_until(&connection, 300).unwrap(), Some(33 * DAY_MS - 5 * MINUTE_MS));
+        assert_eq!(rolled_up_until(&connection, 3600).unwrap(), Some(33 * DAY_MS - 60 * MINUTE_MS));
+    }
+
+    #[test]
+    fn test_queries_use_the_best_tier() {
+        let history = History::open_in_memory(default_tiers());
+        for minute in 0..120 {
+            insert_reading(&history, "GVH5075_0001", minute * MINUTE_MS, minute as f32, Some(50.0));
+        }
+        compact_at(&history, 2 * 60 * MINUTE_MS);
+        // the raw readings that were rolled up are gone, so anything coming from them would be missing
+        history.connection.lock().unwrap().execute("DELETE FROM readings WHERE timestamp_ms < ?", [60 * MINUTE_MS]).unwrap();
+
+        let hourly = HistoryQuery { device: "GVH5075_0001".to_string(), start_ms: 0, end_ms: 120 * MINUTE_MS, bucket_ms: 60 * MINUTE_MS };
+        let buckets = history.query(&hourly).unwrap();
+        assert_eq!(buckets.iter().map(|bucket| bucket.count).collect::<Vec<_>>(), vec![60, 60]);
+        let temperature = buckets[0].temperature_in_c.unwrap();
+        assert_eq!((temperature.min, temperature.mean(), temperature.max, temperature.last), (0.0, 29.5, 59.0, 59.0));
+        assert_eq!(buckets[1].temperature_in_c.unwrap().last, 119.0);
+
+        // 10-minute buckets can be made of the 5-minute rollups, followed by the readings that weren't rolled up yet
+        let query = HistoryQuery { bucket_ms: 10 * MINUTE_MS, ..hourly.clone() };
+        let buckets = history.query(&query).unwrap();
+        assert_eq!(buckets.len(), 12);
+        assert!(buckets.iter().all(|bucket| bucket.count == 10));
+
+        // hourly rollups would leave out the first half hour, so the 5-minute ones are used instead
+        let query = HistoryQuery { start_ms: 30 * MINUTE_MS, ..hourly };
+        let buckets = history.query(&query).unwrap();
+        assert_eq!(buckets.iter().map(|bucket| bucket.count).collect::<Vec<_>>(), vec![60, 30]);
+    }
+
+    #[test]
+    fn test_unaligned_queries_reach_back_past_the_readings() {
+        let history = History::open_in_memory(default_tiers());
+        // every 10 minutes for 10 days, of which only the last 7 days are kept as they are
+        for minute in (0..10 * 24 * 60).step_by(10) {
+            insert_reading(&history, "GVH5075_0001", minute * MINUTE_MS, 20.0, None);
+        }
+        compact_at(&history, 10 * DAY_MS);
+        assert_eq!(count(&history, "readings WHERE timestamp_ms < 3 * 24 * 60 * 60 * 1000"), 0);
+
+        // starting at none of the resolutions, and long before the oldest reading
+        let start_ms = DAY_MS + 17 * MINUTE_MS;
+        let query = HistoryQuery { device: "GVH5075_0001".to_string(), start_ms, end_ms: 3 * DAY_MS, bucket_ms: 60 * MINUTE_MS };
+        let buckets = history.query(&query).unwrap();
+        assert_eq!(buckets.len(), 48);
+        assert_eq!(buckets[0].start_ms, start_ms);
+        assert!(buckets.iter().all(|bucket| bucket.count == 6));
+
+        // the hourly rollups up to the oldest reading, and the readings from then on
+        let query = HistoryQuery { start_ms: 2 * DAY_MS + 17 * MINUTE_MS, end_ms: 4 * DAY_MS + 17 * MINUTE_MS, ..query };
+        let counts: Vec<u64> = history.query(&query).unwrap().iter().map(|bucket| bucket.count).collect();
+        assert_eq!(counts.len(), 48);
+        // the hour before the oldest reading is accurate to within half an hour
+        assert_eq!(counts[23], 8);
+        assert!(counts.iter().enumerate().all(|(index, &count)| index == 23 || count == 6));
+    }
+}
@@ -0,0 +1,280 @@
+use rusqlite::types::ToSql;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+
+use super::{History, Tier};
+
+/// The readings of a device within a time range (in milliseconds since the epoch, the end being exclusive),
+/// summarized over buckets of the given size.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryQuery {
+    pub device: String,
+    pub start_ms: i64,
+    pub end_ms: i64,
+    pub bucket_ms: i64,
+}
+
+/// The values of a single quantity within a bucket.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Summary {
+    pub count: u64,
+    pub min: f32,
+    pub max: f32,
+    pub sum: f64,
+    pub last: f32,
+}
+
+impl Summary {
+    fn of(value: f32) -> Summary {
+        Summary { count: 1, min: value, max: value, sum: value as f64, last: value }
+    }
+
+    pub fn mean(&self) -> f32 {
+        (self.sum / self.count as f64) as f32
+    }
+
+    /// Adds the values that came after the ones summarized so far.
+    fn extend(&mut self, later: Summary) {
+        self.count += later.count;
+        self.min = self.min.min(later.min);
+        self.max = self.max.max(later.max);
+        self.sum += later.sum;
+        self.last = later.last;
+    }
+}
+
+/// The readings within `[start_ms, start_ms + bucket_ms)`. Buckets without any readings are left out.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bucket {
+    pub start_ms: i64,
+    pub count: u64,
+    pub temperature_in_c: Option<Summary>,
+    pub humidity: Option<Summary>,
+    pub battery: Option<Summary>,
+}
+
+impl Bucket {
+    /// Adds the readings that came after the ones in the bucket so far.
+    pub(super) fn extend(&mut self, later: &Bucket) {
+        self.count += later.count;
+        for (summary, later) in [
+            (&mut self.temperature_in_c, later.temperature_in_c),
+            (&mut self.humidity, later.humidity),
+            (&mut self.battery, later.battery),
+        ] {
+            match (summary.as_mut(), later) {
+                (Some(summary), Some(later)) => summary.extend(later),
+                (None, Some(later)) => *summary = Some(later),
+                (_, None) => {}
+            }
+        }
+    }
+}
+
+/// Adds a reading (or a rollup) to the bucket of the given size it falls into, counting from the origin.
+///
+/// The readings have to come in chronological order.
+pub(super) fn add_to_buckets(buckets: &mut Vec<Bucket>, partial: Bucket, origin_ms: i64, bucket_ms: i64) {
+    let start_ms = origin_ms + (partial.start_ms - origin_ms).div_euclid(bucket_ms) * bucket_ms;
+    match buckets.last_mut() {
+        Some(bucket) if bucket.start_ms == start_ms => bucket.extend(&partial),
+        _ => buckets.push(Bucket { start_ms, ..partial }),
+    }
+}
+
+/// Reads the readings (or rollups) kept in the tier within the time range, in chronological order for every device.
+///
+/// Every reading is read as a bucket of its own.
+pub(super) fn read_tier(
+    connection: &Connection,
+    tier: &Tier,
+    device: Option<&str>,
+    start_ms: i64,
+    end_ms: i64,
+    mut visit: impl FnMut(String, Bucket),
+) -> rusqlite::Result<()> {
+    let mut conditions = vec!["timestamp_ms >= ?", "timestamp_ms < ?"];
+    let mut params: Vec<&dyn ToSql> = vec![&start_ms, &end_ms];
+    if let Some(device) = &device {
+        conditions.push("device = ?");
+        params.push(device);
+    }
+    let sql = match &tier.resolution_secs {
+        None => format!(
+            "SELECT device, timestamp_ms, temperature_in_c, humidity, battery FROM readings
+             WHERE {} ORDER BY device, timestamp_ms",
+            conditions.join(" AND "),
+        ),
+        Some(resolution_secs) => {
+            conditions.push("resolution_secs = ?");
+            params.push(resolution_secs);
+            format!(
+                "SELECT device, timestamp_ms, count,
+                    temperature_in_c_count, temperature_in_c_min, temperature_in_c_max, temperature_in_c_sum, temperature_in_c_last,
+                    humidity_count, humidity_min, humidity_max, humidity_sum, humidity_last,
+                    battery_count, battery_min, battery_max, battery_sum, battery_last
+                 FROM rollups WHERE {} ORDER BY device, timestamp_ms",
+                conditions.join(" AND "),
+            )
+        }
+    };
+    let mut statement = connection.prepare_cached(&sql)?;
+    let mut rows = statement.query(params.as_slice())?;
+    while let Some(row) = rows.next()? {
+        let partial = match tier.resolution_secs {
+            None => Bucket {
+                start_ms: row.get(1)?,
+                count: 1,
+                temperature_in_c: row.get::<_, Option<f32>>(2)?.map(Summary::of),
+                humidity: row.get::<_, Option<f32>>(3)?.map(Summary::of),
+                battery: row.get::<_, Option<u8>>(4)?.map(|battery| Summary::of(battery.into())),
+            },
+            Some(_) => Bucket {
+                start_ms: row.get(1)?,
+                count: row.get(2)?,
+                temperature_in_c: read_summary(row, 3)?,
+                humidity: read_summary(row, 8)?,
+                battery: read_summary(row, 13)?,
+            },
+        };
+        visit(row.get(0)?, partial);
+    }
+    Ok(())
+}
+
+fn read_summary(row: &Row, first_column: usize) -> rusqlite::Result<Option<Summary>> {
+    let count: Option<u64> = row.get(first_column)?;
+    count.map(|count| Ok(Summary {
+        count,
+        min: row.get(first_column + 1)?,
+        max: row.get(first_column + 2)?,
+        sum: row.get(first_column + 3)?,
+        last: row.get(first_column + 4)?,
+    })).transpose()
+}
+
+/// Everything before this time has been rolled up into the tier with the given resolution, if anything has.
+pub(super) fn rolled_up_until(connection: &Connection, resolution_secs: u64) -> rusqlite::Result<Option<i64>> {
+    connection.query_row(
+        "SELECT rolled_up_until_ms FROM compaction WHERE resolution_secs = ?",
+        [resolution_secs],
+        |row| row.get(0),
+    ).optional()
+}
+
+/// When the oldest reading (or rollup) of the device still kept in the tier was taken, if there is any.
+fn kept_since(connection: &Connection, tier: &Tier, device: &str) -> rusqlite::Result<Option<i64>> {
+    match tier.resolution_secs {
+        None => connection.query_row(
+            "SELECT MIN(timestamp_ms) FROM readings WHERE device = ?",
+            [device],
+            |row| row.get(0),
+        ),
+        Some(resolution_secs) => connection.query_row(
+            "SELECT MIN(timestamp_ms) FROM rollups WHERE device = ? AND resolution_secs = ?",
+            params![device, resolution_secs],
+            |row| row.get(0),
+        ),
+    }
+}
+
+impl History {
+    /// Summarizes the readings in chronological order.
+    ///
+    /// The readings are taken from the coarsest tier the buckets can be made of exactly, i.e. with rollups
+    /// that start with them (so the time range has to start at a multiple of its resolution too),
+    /// and from the finer tiers for the time that hasn't been rolled up yet.
+    /// If the time range goes back further than that tier keeps, the coarser tiers the buckets are a multiple of
+    /// make up for the rest: their rollups go into the bucket their middle falls into,
+    /// so those buckets are only accurate to within half of the resolution.
+    pub fn query(&self, query: &HistoryQuery) -> rusqlite::Result<Vec<Bucket>> {
+        let connection = self.reader.lock().expect("Could not lock mutex");
+        let fits = |tier: &Tier| tier.resolution_ms().is_none_or(|resolution_ms| query.bucket_ms % resolution_ms == 0);
+        let coarsest = self.tiers.iter().rposition(fits).unwrap_or_default();
+        let exact = self.tiers.iter()
+            .rposition(|tier| fits(tier) && tier.resolution_ms().is_none_or(|resolution_ms| {
+                query.start_ms.rem_euclid(resolution_ms) == 0
+            }))
+            .unwrap_or_default();
+        let kept_since = match coarsest > exact {
+            true => kept_since(&connection, &self.tiers[exact], &query.device)?,
+            false => None,
+        };
+        let mut buckets = vec![];
+        let mut start_ms = query.start_ms;
+        for (index, tier) in self.tiers[..=coarsest].iter().enumerate().rev() {
+            let rolled_up_until = match tier.resolution_secs {
+                Some(resolution_secs) => match rolled_up_until(&connection, resolution_secs)? {
+                    Some(until_ms) => Some(until_ms),
+                    None => continue,
+                },
+                None => None,
+            };
+            if index > exact {
+                let resolution_ms = tier.resolution_ms().expect("only rollups can be inexact");
+                let half_ms = resolution_ms / 2;
+                // up to where the finer tiers take over
+                let until_ms = rolled_up_until.expect("rollups are rolled up until some time");
+                let until_ms = kept_since.map_or(until_ms, |kept_since| {
+                    until_ms.min((kept_since + resolution_ms - 1).div_euclid(resolution_ms) * resolution_ms)
+                });
+                // only the rollups whose middle is within the time range
+                let read_start_ms = match start_ms == query.start_ms {
+                    true => start_ms - half_ms,
+                    false => start_ms,
+                };
+                let (read_end_ms, next_start_ms) = match until_ms >= query.end_ms {
+                    true => (query.end_ms - half_ms, query.end_ms),
+                    false => (until_ms, until_ms.max(query.start_ms)),
+                };
+                if read_end_ms <= read_start_ms {
+                    continue;
+                }
+                read_tier(&connection, tier, Some(&query.device), read_start_ms, read_end_ms, |_, partial| {
+                    let partial = Bucket { start_ms: partial.start_ms + half_ms, ..partial };
+                    add_to_buckets(&mut buckets, partial, query.start_ms, query.bucket_ms);
+                })?;
+                start_ms = next_start_ms;
+                continue;
+            }
+            let end_ms = match rolled_up_until {
+                Some(until_ms) => until_ms.min(query.end_ms),
+                None => query.end_ms,
+            };
+            if end_ms <= start_ms {
+                continue;
+            }
+            read_tier(&connection, tier, Some(&query.device), start_ms, end_ms, |_, partial| {
+                add_to_buckets(&mut buckets, partial, query.start_ms, query.bucket_ms);
+            })?;
+            start_ms = end_ms;
+        }
+        Ok(buckets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{default_tiers, insert_reading as insert};
+
+    #[test]
+    fn test_readings_are_bucketed() {
+        let history = History::open_in_memory(default_tiers());
+        insert(&history, "GVH5075_0001", 500, 25.0, None);
+        insert(&history, "GVH5075_0001", 1_000, 20.0, Some(40.0));
+        insert(&history, "GVH5075_0001", 61_000, 22.0, None);
+        insert(&history, "GVH5075_0001", 59_000, 21.0, Some(50.0));
+        insert(&history, "GVH5075_0001", 121_000, 23.0, Some(60.0));
+        insert(&history, "GVH5075_0002", 2_000, -18.0, Some(70.0));
+
+        let query = HistoryQuery { device: "GVH5075_0001".to_string(), start_ms: 1_000, end_ms: 121_000, bucket_ms: 60_000 };
+        let buckets = history.query(&query).unwrap();
+        assert_eq!(buckets.iter().map(|bucket| (bucket.start_ms, bucket.count)).collect::<Vec<_>>(),
+            vec![(1_000, 2), (61_000, 1)]);
+        let temperature = buckets[0].temperature_in_c.unwrap();
+        assert_eq!((temperature.min, temperature.mean(), temperature.max, temperature.last), (20.0, 20.5, 21.0, 21.0));
+        assert_eq!(buckets[0].humidity.unwrap().count, 2);
+        assert_eq!(buckets[1].humidity, None);
+        assert_eq!(buckets[1].battery.unwrap().last, 100.0);
+    }
+}
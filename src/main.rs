@@ -1,21 +1,36 @@
 #[macro_use] extern crate log;
 
 use std::error::Error;
+use std::io;
 use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::process;
 use std::sync::Arc;
 
 use env_logger::Env;
 use structopt::StructOpt;
-use tokio::time::{Duration, sleep};
+use tokio::sync::watch;
+use tokio::time::{Duration, Instant, sleep};
 
-use crate::collector::Collector;
-use crate::device_database::DeviceDatabase;
+use crate::collector::{
+    AdvertisementSource, BluetoothSource, Collector, RecordingSource, ReplaySource, ReplaySpeed, SavedState, SimulatedSource,
+};
+use crate::config::{Config, default_history_file, default_state_file, ServiceConfig};
+use crate::device_database::{DeviceDatabase, DevicesFileError, DevicesSource};
+use crate::history::History;
 use crate::server::DeviceDataServer;
 
+mod atomic_write;
 mod collector;
+mod config;
 mod device_database;
+mod history;
+mod retry;
 mod server;
+mod snapshot;
+
+/// How long the requests in progress get to finish once the service is stopping, as streams never do by themselves.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(StructOpt)]
 #[structopt(
@@ -24,31 +39,322 @@ mod server;
     version = env!("VERGEN_SEMVER"),
 )]
 struct Opt {
+    #[structopt(short, long, parse(from_os_str), help = "Selects the service configuration file")]
+    config: Option<PathBuf>,
+
     #[structopt(short = "f", long, parse(from_os_str), help = "Selects a TOML file with the list of devices")]
     devices_file: Option<PathBuf>,
 
-    #[structopt(short, long, help = "Socket address to listen on", default_value="127.0.0.1:50051")]
-    address: SocketAddr,
+    #[structopt(long, help = "Refuses to start if the devices file is missing or has any problems")]
+    strict_config: bool,
+
+    #[structopt(long, conflicts_with = "strict-config", help = "Starts with whatever devices could be loaded, even if strict mode is configured")]
+    no_strict_config: bool,
+
+    #[structopt(short, long, help = "Socket address to listen on [default: 127.0.0.1:50051]")]
+    address: Option<SocketAddr>,
+
+    #[structopt(long, help = "Log level, unless overridden by RUST_LOG [default: info]")]
+    log_level: Option<String>,
+
+    #[structopt(long, help = "How long to wait for the bluetooth adapter at startup (in seconds) [default: 60]")]
+    adapter_timeout: Option<u64>,
+
+    #[structopt(long, help = "Time without advertisements after which a device is considered offline (in seconds) [default: 300]")]
+    stale_after: Option<u64>,
+
+    #[structopt(long, help = "How far back the readings kept in memory go (in hours), or 0 to keep none [default: 24]")]
+    recent_readings_hours: Option<u64>,
+
+    #[structopt(long, parse(from_os_str), help = "Replays advertisements from a capture file instead of scanning")]
+    replay: Option<PathBuf>,
+
+    #[structopt(long, help = "Replay speed multiplier, or \"max\" to replay as fast as possible", default_value = "1")]
+    replay_speed: ReplaySpeed,
+
+    #[structopt(long, parse(from_os_str), conflicts_with = "replay", help = "Simulates the devices described in a TOML file instead of scanning")]
+    simulate: Option<PathBuf>,
+
+    #[structopt(long, parse(from_os_str), help = "Selects the SQLite database the readings are kept in")]
+    history_file: Option<PathBuf>,
+
+    #[structopt(long, parse(from_os_str), help = "Selects the file the latest readings are saved to across restarts")]
+    state_file: Option<PathBuf>,
+
+    #[structopt(long, parse(from_os_str), help = "Records received advertisements into a capture file")]
+    record: Option<PathBuf>,
 
-    #[structopt(short, long, help = "BLE initialization delay (in seconds)", default_value="0")]
-    delay: u8,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    #[structopt(about = "Checks the devices file without starting the service")]
+    ValidateConfig {
+        #[structopt(parse(from_os_str), help = "The devices file to check, instead of the selected one")]
+        file: Option<PathBuf>,
+    },
+}
+
+impl Opt {
+    fn service_config(&self) -> ServiceConfig {
+        ServiceConfig {
+            address: self.address,
+            log_level: self.log_level.clone(),
+            adapter_timeout_secs: self.adapter_timeout,
+            stale_after_secs: self.stale_after,
+            recent_readings_hours: self.recent_readings_hours,
+            devices_file: self.devices_file.clone(),
+            strict_config: match (self.strict_config, self.no_strict_config) {
+                (true, _) => Some(true),
+                (_, true) => Some(false),
+                (false, false) => None,
+            },
+            history_file: self.history_file.clone(),
+            state_file: self.state_file.clone(),
+            history_tiers: None,
+        }
+    }
+}
+
+/// Prints every problem found in the devices file, and exits with a failure if there are any.
+fn validate_config(config: &Config, devices_source: Option<DevicesSource>) -> ! {
+    if let Some(path) = &config.path {
+        println!("{}: OK", path.display());
+    }
+    match DeviceDatabase::validate(devices_source) {
+        Ok((path, device_count)) => {
+            println!("{}: OK ({} devices)", path.display(), device_count);
+            process::exit(0)
+        }
+        Err(DevicesFileError::Invalid { path, problems }) => {
+            for problem in problems {
+                eprintln!("{}:{}:{}: {}", path.display(), problem.line, problem.column, problem.message);
+            }
+            process::exit(1)
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1)
+        }
+    }
+}
+
+/// Waits for the service to be asked to stop, with Ctrl+C or (on unix) SIGTERM.
+async fn shutdown_requested() -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminations = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result,
+            _ = terminations.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
-
     let opt = Opt::from_args();
-    let device_database = Arc::new(DeviceDatabase::new(opt.devices_file)?);
-    sleep(Duration::from_secs(opt.delay as u64)).await;
-    let collector = Arc::new(Collector::new(Arc::clone(&device_database)).await?);
+    // only a service actually collecting from the devices sets up a new install
+    let load = match (&opt.command, &opt.replay, &opt.simulate) {
+        (None, None, None) => Config::load_or_create,
+        _ => Config::load,
+    };
+    let config = match load(opt.service_config(), opt.config.clone()) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1)
+        }
+    };
+    if let Some(Command::ValidateConfig { file }) = opt.command {
+        validate_config(&config, file.map(DevicesSource::DevicesFile).or(config.devices_source.clone()));
+    }
+    // RUST_LOG still takes precedence, as it allows for filtering by module
+    env_logger::Builder::from_env(Env::default().default_filter_or(config.service.log_level())).init();
+    if let Some(path) = &config.path {
+        info!("Using configuration file {:?}", path);
+    }
+
+    let mut device_database = match DeviceDatabase::new(config.devices_source.clone(), config.service.strict_config()) {
+        Ok(device_database) => device_database,
+        Err(err) => {
+            error!("{}", err);
+            error!("Run the validate-config command for the details");
+            process::exit(1)
+        }
+    };
+    // the bluetooth adapter is waited for in the background, so that the gRPC server can start right away
+    let source: Option<Box<dyn AdvertisementSource>> = match (&opt.replay, &opt.simulate) {
+        (Some(path), _) => Some(Box::new(ReplaySource::open(path, opt.replay_speed)?)),
+        (None, Some(path)) => {
+            let simulated_source = SimulatedSource::load(path)?;
+            for (local_name, friendly_name) in simulated_source.devices() {
+                device_database.add_device(local_name.clone(), friendly_name.clone());
+            }
+            Some(Box::new(simulated_source))
+        }
+        (None, None) => None,
+    };
+    let device_database = Arc::new(device_database);
+    let collector = Arc::new(Collector::new(
+        Arc::clone(&device_database),
+        Duration::from_secs(config.service.stale_after_secs()),
+        Duration::from_secs(config.service.recent_readings_hours() * 60 * 60),
+    ));
+    // replayed and simulated readings only go to a history (or a state file) that was asked for explicitly
+    let (history_file, state_file) = match source {
+        Some(_) => (config.service.history_file.clone(), config.service.state_file.clone()),
+        None => (
+            config.service.history_file.clone().or_else(default_history_file),
+            config.service.state_file.clone().or_else(default_state_file),
+        ),
+    };
+    if let Some(path) = &state_file {
+        match SavedState::read(path) {
+            Ok(Some(state)) => collector.restore(state),
+            Ok(None) => info!("No state to restore at {:?}", path),
+            // the readings will come in again soon enough
+            Err(err) => warn!("Unable to restore the state from {:?}: {}", path, err),
+        }
+    }
+    let history = history_file.map(|path| match History::open(&path, config.service.history_tiers()) {
+        Ok(history) => {
+            info!("Keeping the history in {:?}", path);
+            Arc::new(history)
+        }
+        Err(err) => {
+            error!("Unable to open the history database at {:?}: {}", path, err);
+            process::exit(1)
+        }
+    });
+    // set once a shutdown signal arrives
+    let (stopping, stopped) = watch::channel(false);
+    let mut recording = None;
+    if let Some(history) = &history {
+        {
+            let history = Arc::clone(history);
+            // subscribed before the collector starts, so that no reading is missed
+            let readings = collector.subscribe_readings("History");
+            let mut stopped = stopped.clone();
+            recording = Some(tokio::spawn(async move {
+                history.record(readings, async move { let _ = stopped.changed().await; }).await;
+            }));
+        }
+        {
+            let history = Arc::clone(history);
+            tokio::spawn(async move {
+                history.compact_periodically().await;
+            });
+        }
+    }
+    // ends with an error (as a message, as the error isn't necessarily Send) if nothing can be collected
+    let mut collecting = {
+        let collector = Arc::clone(&collector);
+        let adapter_timeout = Duration::from_secs(config.service.adapter_timeout_secs());
+        tokio::spawn(async move {
+            let deadline = Instant::now() + adapter_timeout;
+            // the adapter can show up a little before it's able to scan, while other sources either work or don't
+            let retry_until = match source {
+                Some(_) => Instant::now(),
+                None => deadline,
+            };
+            let source = match source {
+                Some(source) => source,
+                None => match BluetoothSource::wait_for_adapter(adapter_timeout).await {
+                    Ok(source) => Box::new(source),
+                    Err(err) => return Err(format!("Unable to find a bluetooth adapter within {:?}: {}", adapter_timeout, err)),
+                },
+            };
+            let source: Box<dyn AdvertisementSource> = match opt.record {
+                Some(path) => Box::new(RecordingSource::new(source, path)),
+                None => source,
+            };
+            match collector.start_retrying(source.as_ref(), retry_until).await {
+                Ok(()) => Ok(()),
+                Err(err) => Err(format!("Unable to receive advertisements: {}", err)),
+            }
+        })
+    };
     {
         let collector = Arc::clone(&collector);
         tokio::spawn(async move {
-            collector.start().await.unwrap();
+            collector.monitor_liveness().await;
+        });
+    }
+    if let Some(path) = state_file.clone() {
+        let collector = Arc::clone(&collector);
+        tokio::spawn(async move {
+            collector.save_state_periodically(&path).await;
         });
     }
-    info!("Starting gRPC server at {}", &opt.address);
-    DeviceDataServer::serve(device_database, collector, opt.address).await?;
+    {
+        let device_database = Arc::clone(&device_database);
+        tokio::spawn(async move {
+            device_database.watch_devices_file().await;
+        });
+    }
+    #[cfg(unix)]
+    {
+        let device_database = Arc::clone(&device_database);
+        tokio::spawn(async move {
+            if let Err(err) = device_database.reload_on_hangup().await {
+                error!("Unable to listen for SIGHUP: {}", err);
+            }
+        });
+    }
+    let address = config.service.address();
+    info!("Starting gRPC server at {}", address);
+    let serving = {
+        let mut stopped = stopped.clone();
+        let shutdown = async move {
+            let _ = stopped.changed().await;
+        };
+        DeviceDataServer::serve(device_database, Arc::clone(&collector), history, address, shutdown)
+    };
+    tokio::pin!(serving);
+    let signalled = async {
+        match shutdown_requested().await {
+            Ok(()) => info!("Shutting down"),
+            Err(err) => {
+                error!("Unable to listen for shutdown signals: {}", err);
+                // keeps serving, as it would have without them
+                std::future::pending::<()>().await
+            }
+        }
+    };
+    let failed = tokio::select! {
+        // only on errors, as it's never asked to stop before this
+        result = &mut serving => return result,
+        _ = signalled => false,
+        Ok(Err(message)) = &mut collecting => {
+            error!("{}", message);
+            true
+        }
+    };
+    // right away, in case the requests in progress hold up the rest
+    if let Some(path) = &state_file {
+        collector.save_state(path).await;
+    }
+    let _ = stopping.send(true);
+    tokio::select! {
+        result = serving => result?,
+        _ = sleep(SHUTDOWN_TIMEOUT) => warn!("Closing the requests still in progress after {:?}", SHUTDOWN_TIMEOUT),
+    }
+    if let Some(recording) = recording {
+        // the readings received since the last batch was written
+        recording.await?;
+    }
+    // once more, with whatever came in while the requests were finishing
+    if let Some(path) = &state_file {
+        collector.save_state(path).await;
+    }
+    if failed {
+        process::exit(1)
+    }
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,91 @@
+use std::error::Error;
+use std::future::Future;
+use std::time::Duration;
+
+use tokio::time::{sleep, Instant};
+
+/// How long to wait between attempts, doubling after every one of them up to the maximum.
+#[derive(Debug, Copy, Clone)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Backoff {
+    /// Always waits just as long.
+    pub const fn constant(delay: Duration) -> Backoff {
+        Backoff { initial: delay, max: delay }
+    }
+}
+
+/// Keeps making attempts until one of them succeeds, or until the deadline passes,
+/// in which case the error of the last attempt (made right at the deadline) is returned.
+///
+/// `failing` describes what a failed attempt means, e.g. "Bluetooth adapter is not ready".
+pub async fn retry<T, F, Fut>(backoff: Backoff, deadline: Instant, failing: &str, mut attempt: F) -> Result<T, Box<dyn Error>>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Box<dyn Error>>>,
+{
+    let mut delay = backoff.initial;
+    loop {
+        // not kept across the sleep, as the error isn't necessarily Send
+        let message = match attempt().await {
+            Ok(value) => return Ok(value),
+            Err(err) if Instant::now() >= deadline => return Err(err),
+            Err(err) => err.to_string(),
+        };
+        let next_delay = delay.min(deadline.saturating_duration_since(Instant::now()));
+        info!("{} ({}), retrying in {:?}", failing, message, next_delay);
+        sleep(next_delay).await;
+        delay = (delay * 2).min(backoff.max);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    /// Makes attempts that fail until the given one, and returns when each of them was made,
+    /// in tenths of a second (as every sleep takes a millisecond longer).
+    async fn attempts(backoff: Backoff, timeout: Duration, succeeding: Option<usize>) -> (bool, Vec<u128>) {
+        tokio::time::pause();
+        let start = Instant::now();
+        let made = RefCell::new(vec![]);
+        let result = retry(backoff, start + timeout, "Not ready", || {
+            made.borrow_mut().push(start.elapsed().as_millis() / 100);
+            let succeeded = succeeding == Some(made.borrow().len());
+            async move {
+                match succeeded {
+                    true => Ok(()),
+                    false => Err("not yet".into()),
+                }
+            }
+        }).await;
+        (result.is_ok(), made.into_inner())
+    }
+
+    #[tokio::test]
+    async fn test_delays_double_up_to_the_maximum_and_end_at_the_deadline() {
+        let backoff = Backoff { initial: Duration::from_millis(500), max: Duration::from_secs(4) };
+        let (succeeded, made) = attempts(backoff, Duration::from_secs(15), None).await;
+        assert!(!succeeded);
+        assert_eq!(made, vec![0, 5, 15, 35, 75, 115, 150]);
+    }
+
+    #[tokio::test]
+    async fn test_retrying_stops_once_an_attempt_succeeds() {
+        let (succeeded, made) = attempts(Backoff::constant(Duration::from_secs(1)), Duration::from_secs(60), Some(3)).await;
+        assert!(succeeded);
+        assert_eq!(made, vec![0, 10, 20]);
+    }
+
+    #[tokio::test]
+    async fn test_there_is_a_single_attempt_without_time_left() {
+        let (succeeded, made) = attempts(Backoff::constant(Duration::from_secs(1)), Duration::ZERO, None).await;
+        assert!(!succeeded);
+        assert_eq!(made, vec![0]);
+    }
+}
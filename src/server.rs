@@ -1,22 +1,64 @@
 use std::error::Error;
+use std::future::Future;
 use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::time::Duration;
 
+use futures::{Stream, stream, StreamExt};
 use tonic::{Request, Response, Status};
 use tonic::transport::Server;
 
-use govee_collector::{GetDeviceDataRequest, GetDeviceDataResponse, StreamDeviceDataRequest};
+use aggregates::get_aggregates;
+use devices::{add_device, get_device, list_devices, remove_device, rename_device, update_device};
+use govee_collector::{
+    AddDeviceRequest,
+    CollectorState,
+    Device,
+    GetAggregatesRequest,
+    GetAggregatesResponse,
+    GetCollectorStatusRequest,
+    GetCollectorStatusResponse,
+    GetDeviceDataRequest,
+    GetDeviceDataResponse,
+    GetDeviceRequest,
+    GetDiagnosticsRequest,
+    GetDiagnosticsResponse,
+    ListDevicesRequest,
+    ListDevicesResponse,
+    QueryHistoryRequest,
+    RemoveDeviceRequest,
+    RemoveDeviceResponse,
+    RenameDeviceRequest,
+    StreamDeviceDataRequest,
+    StreamDeviceDataResponse,
+    StreamDeviceStatusRequest,
+    StreamMode,
+    UpdateDeviceRequest,
+};
 use govee_collector::device_data_provider_server::{DeviceDataProvider, DeviceDataProviderServer};
-use stream_device_data::DeviceDataStream;
+use query_history::{HistoryStream, query_history};
+use stream_device_data::SnapshotHub;
+use stream_device_data_changes::{ChangeFilter, stream_device_data_changes};
+use stream_device_status::DeviceStatusStream;
 use utils::extract_device_data;
-use utils::resolve_unique_ids;
+use utils::extract_diagnostics;
+use utils::replay_readings;
+use utils::DeviceSelection;
+use utils::to_refresh_interval;
+use utils::to_recent_window;
+use utils::add_recent_readings;
 
-use crate::collector::Collector;
+use crate::collector::{self, Collector};
 use crate::device_database::DeviceDatabase;
+use crate::history::History;
 
+mod aggregates;
+mod devices;
+mod query_history;
 mod stream_device_data;
+mod stream_device_data_changes;
+mod stream_device_status;
 mod utils;
 
 mod govee_collector {
@@ -26,18 +68,23 @@ mod govee_collector {
 pub struct DeviceDataServer {
     device_database: Arc<DeviceDatabase>,
     collector: Arc<Collector>,
+    history: Option<Arc<History>>,
+    snapshot_hub: SnapshotHub,
 }
 
 impl DeviceDataServer {
     pub async fn serve(
         device_database: Arc<DeviceDatabase>,
         collector: Arc<Collector>,
+        history: Option<Arc<History>>,
         address: SocketAddr,
+        shutdown: impl Future<Output = ()>,
     ) -> Result<(), Box<dyn Error>> {
-        let server = DeviceDataServer { device_database, collector };
+        let snapshot_hub = SnapshotHub::new(Arc::clone(&collector), Arc::clone(&device_database));
+        let server = DeviceDataServer { device_database, collector, history, snapshot_hub };
         Server::builder()
             .add_service(DeviceDataProviderServer::new(server))
-            .serve(address)
+            .serve_with_shutdown(address, shutdown)
             .await?;
         Ok(())
     }
@@ -50,13 +97,19 @@ impl DeviceDataProvider for DeviceDataServer {
         request: Request<GetDeviceDataRequest>,
     ) -> Result<Response<GetDeviceDataResponse>, Status> {
         debug!("Got a request {:?}", request);
-        let unique_ids = resolve_unique_ids(&self.device_database, request.into_inner().unique_ids);
-        let devices = extract_device_data(&self.collector, &self.device_database, &unique_ids).await;
+        let request = request.into_inner();
+        let selection = DeviceSelection::new(&self.device_database, request.unique_ids, request.selectors, request.lenient)?;
+        let kept = self.collector.recent_readings_window();
+        let recent_readings_window = to_recent_window(request.recent_readings_in_secs, kept, "recent readings window")?;
+        let extremes_window = to_recent_window(request.extremes_window_in_secs, kept, "extremes window")?;
+        let unique_ids = selection.resolve(&self.device_database);
+        let mut devices = extract_device_data(&self.collector, &self.device_database, &unique_ids, selection.includes_unknown());
+        add_recent_readings(&self.collector, &mut devices, recent_readings_window, extremes_window);
         let reply = GetDeviceDataResponse { devices };
         Ok(Response::new(reply))
     }
 
-    type StreamDeviceDataStream = Pin<Box<DeviceDataStream>>;
+    type StreamDeviceDataStream = Pin<Box<dyn Stream<Item = Result<StreamDeviceDataResponse, Status>> + Send + Sync>>;
 
     async fn stream_device_data(
         &self,
@@ -64,12 +117,151 @@ impl DeviceDataProvider for DeviceDataServer {
     ) -> Result<Response<Self::StreamDeviceDataStream>, Status> {
         debug!("Client connected from: {:?} with request {:?}", request.remote_addr(), request);
         let request = request.into_inner();
-        let device_data_stream = Box::pin(DeviceDataStream::new(
-            Duration::from_secs(request.refresh_interval_in_secs.unwrap_or(60) as u64),
-            Arc::clone(&self.collector),
+        let selection = DeviceSelection::new(&self.device_database, request.unique_ids, request.selectors, request.lenient)?;
+        let device_data_stream: Self::StreamDeviceDataStream = match StreamMode::from_i32(request.mode) {
+            Some(StreamMode::Periodic) => {
+                let refresh_interval = to_refresh_interval(request.refresh_interval_in_secs)?;
+                // the periodic stream starts with a snapshot anyway, so there is no need to fall back to one
+                let replayed = match request.resume_from_sequence_number {
+                    Some(sequence_number) => {
+                        let unique_ids = selection.resolve(&self.device_database);
+                        replay_readings(&self.collector, &self.device_database, &unique_ids, sequence_number)
+                    }
+                    None => None,
+                };
+                let snapshots = self.snapshot_hub.subscribe(selection, refresh_interval).await;
+                Box::pin(stream::iter(replayed.unwrap_or_default().into_iter().map(Ok)).chain(snapshots))
+            }
+            Some(StreamMode::OnChange) => Box::pin(stream_device_data_changes(
+                Arc::clone(&self.collector),
+                Arc::clone(&self.device_database),
+                selection,
+                Duration::from_millis(request.min_interval_in_ms.unwrap_or(0) as u64),
+                ChangeFilter::new(
+                    request.temperature_deadband_in_c,
+                    request.humidity_deadband,
+                    request.battery_deadband,
+                )?,
+                request.resume_from_sequence_number,
+            )),
+            None => return Err(Status::invalid_argument(format!("unknown stream mode {}", request.mode))),
+        };
+        Ok(Response::new(device_data_stream))
+    }
+
+    type StreamDeviceStatusStream = Pin<Box<DeviceStatusStream>>;
+
+    async fn stream_device_status(
+        &self,
+        request: Request<StreamDeviceStatusRequest>,
+    ) -> Result<Response<Self::StreamDeviceStatusStream>, Status> {
+        debug!("Client connected from: {:?} with request {:?}", request.remote_addr(), request);
+        let request = request.into_inner();
+        let device_status_stream = Box::pin(DeviceStatusStream::new(
+            self.collector.subscribe_liveness_changes(),
             Arc::clone(&self.device_database),
-            Arc::new(resolve_unique_ids(&self.device_database, request.unique_ids))
+            DeviceSelection::new(&self.device_database, request.unique_ids, vec![], false)?,
         ));
-        Ok(Response::new(device_data_stream))
+        Ok(Response::new(device_status_stream))
+    }
+
+    async fn get_diagnostics(
+        &self,
+        request: Request<GetDiagnosticsRequest>,
+    ) -> Result<Response<GetDiagnosticsResponse>, Status> {
+        debug!("Got a request {:?}", request);
+        let selection = DeviceSelection::new(&self.device_database, request.into_inner().unique_ids, vec![], false)?;
+        let unique_ids = selection.resolve(&self.device_database);
+        let devices = extract_diagnostics(&self.collector, &self.device_database, &unique_ids).await;
+        let reply = GetDiagnosticsResponse { devices };
+        Ok(Response::new(reply))
+    }
+
+    async fn list_devices(
+        &self,
+        request: Request<ListDevicesRequest>,
+    ) -> Result<Response<ListDevicesResponse>, Status> {
+        debug!("Got a request {:?}", request);
+        let reply = list_devices(&self.collector, &self.device_database, request.into_inner())?;
+        Ok(Response::new(reply))
+    }
+
+    async fn get_device(
+        &self,
+        request: Request<GetDeviceRequest>,
+    ) -> Result<Response<Device>, Status> {
+        debug!("Got a request {:?}", request);
+        let reply = get_device(&self.collector, &self.device_database, &request.into_inner().unique_id)?;
+        Ok(Response::new(reply))
+    }
+
+    async fn add_device(
+        &self,
+        request: Request<AddDeviceRequest>,
+    ) -> Result<Response<Device>, Status> {
+        debug!("Got a request {:?}", request);
+        let reply = add_device(&self.collector, &self.device_database, request.into_inner()).await?;
+        Ok(Response::new(reply))
+    }
+
+    async fn rename_device(
+        &self,
+        request: Request<RenameDeviceRequest>,
+    ) -> Result<Response<Device>, Status> {
+        debug!("Got a request {:?}", request);
+        let reply = rename_device(&self.collector, &self.device_database, request.into_inner()).await?;
+        Ok(Response::new(reply))
+    }
+
+    async fn update_device(
+        &self,
+        request: Request<UpdateDeviceRequest>,
+    ) -> Result<Response<Device>, Status> {
+        debug!("Got a request {:?}", request);
+        let reply = update_device(&self.collector, &self.device_database, request.into_inner()).await?;
+        Ok(Response::new(reply))
+    }
+
+    async fn remove_device(
+        &self,
+        request: Request<RemoveDeviceRequest>,
+    ) -> Result<Response<RemoveDeviceResponse>, Status> {
+        debug!("Got a request {:?}", request);
+        let reply = remove_device(&self.device_database, request.into_inner()).await?;
+        Ok(Response::new(reply))
+    }
+
+    async fn get_aggregates(
+        &self,
+        request: Request<GetAggregatesRequest>,
+    ) -> Result<Response<GetAggregatesResponse>, Status> {
+        debug!("Got a request {:?}", request);
+        let reply = get_aggregates(&self.collector, &self.device_database, request.into_inner())?;
+        Ok(Response::new(reply))
     }
-}
\ No newline at end of file
+
+    async fn get_collector_status(
+        &self,
+        request: Request<GetCollectorStatusRequest>,
+    ) -> Result<Response<GetCollectorStatusResponse>, Status> {
+        debug!("Got a request {:?}", request);
+        let state = match self.collector.get_state() {
+            collector::CollectorState::Initializing => CollectorState::Initializing,
+            collector::CollectorState::Running => CollectorState::Running,
+            collector::CollectorState::Stopped => CollectorState::Stopped,
+        };
+        let reply = GetCollectorStatusResponse { state: state as i32 };
+        Ok(Response::new(reply))
+    }
+
+    type QueryHistoryStream = HistoryStream;
+
+    async fn query_history(
+        &self,
+        request: Request<QueryHistoryRequest>,
+    ) -> Result<Response<Self::QueryHistoryStream>, Status> {
+        debug!("Got a request {:?}", request);
+        let stream = query_history(self.history.as_ref(), &self.device_database, request.into_inner())?;
+        Ok(Response::new(stream))
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::BTreeMap;
+
+use tonic::Status;
+
+use crate::collector::Collector;
+use crate::device_database::DeviceDatabase;
+
+use super::govee_collector::{Aggregate, GetAggregatesRequest, GetAggregatesResponse, GroupAggregate, GroupBy};
+use super::utils::DeviceSelection;
+
+/// Summarizes the latest readings of the devices in every room (or with every tag), ordered by the groups.
+///
+/// Devices that are offline or haven't sent any readings yet are left out, so that they can't skew the numbers.
+pub fn get_aggregates(
+    collector: &Collector,
+    device_database: &DeviceDatabase,
+    request: GetAggregatesRequest,
+) -> Result<GetAggregatesResponse, Status> {
+    let group_by = GroupBy::from_i32(request.group_by)
+        .ok_or_else(|| Status::invalid_argument(format!("unknown grouping {}", request.group_by)))?;
+    let selection = DeviceSelection::new(device_database, vec![], request.selectors, false)?;
+    let unique_ids = selection.resolve(device_database);
+    // all the devices are taken from the same snapshot, even if readings arrive in the meantime
+    let readings = collector.get_latest_readings();
+    let groups = group_devices(device_database, unique_ids, group_by, request.groups).into_iter()
+        .map(|(group, members)| {
+            let (unique_ids, excluded_unique_ids): (Vec<String>, Vec<String>) = members.into_iter()
+                .partition(|local_name| readings.contains_key(local_name) && collector.get_liveness(local_name).online);
+            let member_readings: Vec<_> = unique_ids.iter().filter_map(|local_name| readings.get(local_name)).collect();
+            GroupAggregate {
+                group,
+                temperature_in_c: aggregate(member_readings.iter().filter_map(|reading| reading.data.temperature_in_c())),
+                humidity: aggregate(member_readings.iter().filter_map(|reading| reading.data.humidity())),
+                unique_ids,
+                excluded_unique_ids,
+            }
+        })
+        .collect();
+    Ok(GetAggregatesResponse { groups })
+}
+
+/// Maps every group to its members, keeping the order of the devices.
+///
+/// Groups that were asked for explicitly are there even without members.
+fn group_devices(
+    device_database: &DeviceDatabase,
+    unique_ids: Vec<String>,
+    group_by: GroupBy,
+    requested: Vec<String>,
+) -> BTreeMap<String, Vec<String>> {
+    let devices = device_database.get_all_devices();
+    let all_groups = requested.is_empty();
+    let mut groups: BTreeMap<String, Vec<String>> = requested.into_iter().map(|group| (group, vec![])).collect();
+    for local_name in unique_ids {
+        let device = match devices.get(&local_name) {
+            Some(device) => device,
+            None => continue,
+        };
+        let device_groups = match group_by {
+            GroupBy::Room => device.room.iter().cloned().collect(),
+            GroupBy::Tag => device.tags.clone(),
+        };
+        for group in device_groups {
+            match groups.get_mut(&group) {
+                Some(members) => members.push(local_name.clone()),
+                None if all_groups => {
+                    groups.insert(group, vec![local_name.clone()]);
+                }
+                None => {}
+            }
+        }
+    }
+    groups
+}
+
+fn aggregate(values: impl Iterator<Item = f32>) -> Option<Aggregate> {
+    let values: Vec<f32> = values.collect();
+    if values.is_empty() {
+        return None;
+    }
+    Some(Aggregate {
+        min: values.iter().copied().fold(f32::INFINITY, f32::min),
+        mean: values.iter().sum::<f32>() / values.len() as f32,
+        max: values.iter().copied().fold(f32::NEG_INFINITY, f32::max),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEVICES: &str = r#"
+        [GVH5075_0001]
+        friendly_name = "Chest Freezer"
+        room = "basement"
+        tags = ["freezer"]
+
+        [GVH5075_0002]
+        friendly_name = "Workshop"
+        room = "basement"
+
+        [GVH5075_0003]
+        friendly_name = "Fridge Freezer"
+        room = "kitchen"
+        tags = ["freezer"]
+
+        [GVH5075_0004]
+        friendly_name = "Garden"
+    "#;
+
+    #[test]
+    fn test_devices_are_grouped() {
+        let device_database = DeviceDatabase::parse(DEVICES);
+        let all = DeviceSelection::new(&device_database, vec![], vec![], false).unwrap().resolve(&device_database);
+        let rooms = group_devices(&device_database, all.clone(), GroupBy::Room, vec![]);
+        assert_eq!(rooms.keys().collect::<Vec<_>>(), vec!["basement", "kitchen"]);
+        assert_eq!(rooms["basement"], vec!["GVH5075_0001", "GVH5075_0002"]);
+        let tags = group_devices(&device_database, all, GroupBy::Tag, vec!["freezer".to_string(), "attic".to_string()]);
+        assert_eq!(tags["freezer"], vec!["GVH5075_0001", "GVH5075_0003"]);
+        assert!(tags["attic"].is_empty());
+    }
+
+    #[test]
+    fn test_aggregates() {
+        assert_eq!(aggregate([20.5, 22.0, 21.5].into_iter()), Some(Aggregate { min: 20.5, mean: 21.333334, max: 22.0 }));
+        assert_eq!(aggregate(std::iter::empty()), None);
+    }
+}
@@ -0,0 +1,293 @@
+use std::sync::Arc;
+
+use tokio::task::spawn_blocking;
+use tonic::Status;
+
+use crate::collector::Collector;
+use crate::device_database::{self as database, DeviceDatabase, DeviceDatabaseError, Selector};
+
+use super::govee_collector::{
+    AddDeviceRequest,
+    Calibration,
+    Device,
+    DeviceConfig,
+    ListDevicesRequest,
+    ListDevicesResponse,
+    RemoveDeviceRequest,
+    RemoveDeviceResponse,
+    RenameDeviceRequest,
+    UpdateDeviceRequest,
+};
+use super::utils::to_unix_millis;
+
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
+
+/// Describes a configured device, along with what the collector has found out about it.
+pub fn to_device(collector: &Collector, device_database: &DeviceDatabase, local_name: &str) -> Option<Device> {
+    let device = device_database.get_device(local_name)?;
+    let liveness = collector.get_liveness(local_name);
+    Some(Device {
+        unique_id: local_name.to_string(),
+        friendly_name: device.friendly_name,
+        model: format!("{:?}", device.model),
+        address: collector.get_address(local_name),
+        room: device.room,
+        floor: device.floor,
+        location: device.location.map(|location| location.to_string()),
+        tags: device.tags,
+        notes: device.notes,
+        calibration: Some(Calibration {
+            temperature_offset_in_c: device.calibration.temperature_offset_in_c,
+            humidity_offset: device.calibration.humidity_offset,
+        }),
+        stale_after_in_secs: device.stale_after_secs,
+        temperature_expression: device.temperature.map(|expression| expression.to_string()),
+        humidity_expression: device.humidity.map(|expression| expression.to_string()),
+        online: liveness.online,
+        last_seen_timestamp: liveness.last_seen.and_then(to_unix_millis),
+    })
+}
+
+pub fn get_device(collector: &Collector, device_database: &DeviceDatabase, unique_id: &str) -> Result<Device, Status> {
+    to_device(collector, device_database, unique_id)
+        .ok_or_else(|| Status::not_found(format!("unknown device: {}", unique_id)))
+}
+
+/// Lists the devices in the room (if any) that have all the requested tags and match all the selectors,
+/// ordered by their ids.
+///
+/// The page token is the id of the last device on the previous page.
+pub fn list_devices(
+    collector: &Collector,
+    device_database: &DeviceDatabase,
+    request: ListDevicesRequest,
+) -> Result<ListDevicesResponse, Status> {
+    let selectors = request.selectors.iter()
+        .map(|selector| selector.parse())
+        .collect::<Result<Vec<Selector>, _>>()
+        .map_err(to_status)?;
+    let page_size = match request.page_size as usize {
+        0 => DEFAULT_PAGE_SIZE,
+        page_size => page_size.min(MAX_PAGE_SIZE),
+    };
+    let devices = device_database.get_all_devices();
+    let mut unique_ids: Vec<&String> = devices.iter()
+        .filter(|(local_name, _)| request.page_token.is_empty() || local_name.as_str() > request.page_token.as_str())
+        .filter(|(_, device)| request.room.as_ref().is_none_or(|room| device.room.as_ref() == Some(room)))
+        .filter(|(_, device)| request.tags.iter().all(|tag| device.tags.contains(tag)))
+        .filter(|(_, device)| selectors.iter().all(|selector| selector.matches(device)))
+        .map(|(local_name, _)| local_name)
+        .collect();
+    unique_ids.sort();
+    let has_more = unique_ids.len() > page_size;
+    unique_ids.truncate(page_size);
+    let next_page_token = match has_more {
+        true => unique_ids.last().map(|local_name| local_name.to_string()).unwrap_or_default(),
+        false => String::new(),
+    };
+    let devices = unique_ids.into_iter()
+        .filter_map(|local_name| to_device(collector, device_database, local_name))
+        .collect();
+    Ok(ListDevicesResponse { devices, next_page_token })
+}
+
+pub async fn add_device(
+    collector: &Collector,
+    device_database: &Arc<DeviceDatabase>,
+    request: AddDeviceRequest,
+) -> Result<Device, Status> {
+    let unique_id = validate_unique_id(request.unique_id)?;
+    let device = to_database_device(request.config)?;
+    let local_name = unique_id.clone();
+    change_devices(device_database, move |database| database.insert_device(&local_name, device)).await?;
+    get_device(collector, device_database, &unique_id)
+}
+
+pub async fn rename_device(
+    collector: &Collector,
+    device_database: &Arc<DeviceDatabase>,
+    request: RenameDeviceRequest,
+) -> Result<Device, Status> {
+    let friendly_name = validate_friendly_name(request.friendly_name)?;
+    let local_name = request.unique_id.clone();
+    change_devices(device_database, move |database| {
+        database.update_device(&local_name, |device| device.friendly_name = friendly_name)
+    }).await?;
+    get_device(collector, device_database, &request.unique_id)
+}
+
+/// Replaces the whole configuration of the device.
+pub async fn update_device(
+    collector: &Collector,
+    device_database: &Arc<DeviceDatabase>,
+    request: UpdateDeviceRequest,
+) -> Result<Device, Status> {
+    let updated = to_database_device(request.config)?;
+    let local_name = request.unique_id.clone();
+    change_devices(device_database, move |database| {
+        database.update_device(&local_name, |device| *device = updated)
+    }).await?;
+    get_device(collector, device_database, &request.unique_id)
+}
+
+pub async fn remove_device(
+    device_database: &Arc<DeviceDatabase>,
+    request: RemoveDeviceRequest,
+) -> Result<RemoveDeviceResponse, Status> {
+    change_devices(device_database, move |database| database.remove_device(&request.unique_id)).await?;
+    Ok(RemoveDeviceResponse {})
+}
+
+/// Applies the change on a blocking thread, as saving it writes and syncs the devices file.
+async fn change_devices<T: Send + 'static>(
+    device_database: &Arc<DeviceDatabase>,
+    change: impl FnOnce(&DeviceDatabase) -> Result<T, DeviceDatabaseError> + Send + 'static,
+) -> Result<T, Status> {
+    let device_database = Arc::clone(device_database);
+    spawn_blocking(move || change(&device_database))
+        .await
+        .expect("Saving the devices panicked")
+        .map_err(to_status)
+}
+
+fn to_database_device(config: Option<DeviceConfig>) -> Result<database::Device, Status> {
+    let config = config.ok_or_else(|| Status::invalid_argument("missing device config"))?;
+    let model = match config.model.as_str() {
+        "" => database::Model::default(),
+        model => model.parse().map_err(to_status)?,
+    };
+    let location = config.location.map(|location| location.parse()).transpose().map_err(to_status)?;
+    let calibration = config.calibration.unwrap_or_default();
+    Ok(database::Device {
+        friendly_name: validate_friendly_name(config.friendly_name)?,
+        model,
+        room: config.room,
+        floor: config.floor,
+        location,
+        tags: config.tags,
+        notes: config.notes,
+        calibration: database::Calibration {
+            temperature_offset_in_c: calibration.temperature_offset_in_c,
+            humidity_offset: calibration.humidity_offset,
+        },
+        stale_after_secs: config.stale_after_in_secs,
+        temperature: parse_expression(config.temperature_expression)?,
+        humidity: parse_expression(config.humidity_expression)?,
+    })
+}
+
+fn parse_expression(expression: Option<String>) -> Result<Option<database::Expression>, Status> {
+    expression.map(|expression| expression.parse())
+        .transpose()
+        .map_err(|err| to_status(DeviceDatabaseError::from(err)))
+}
+
+fn validate_unique_id(unique_id: String) -> Result<String, Status> {
+    match unique_id.trim().is_empty() {
+        true => Err(Status::invalid_argument("unique id must not be empty")),
+        false => Ok(unique_id),
+    }
+}
+
+fn validate_friendly_name(friendly_name: String) -> Result<String, Status> {
+    match friendly_name.trim().is_empty() {
+        true => Err(Status::invalid_argument("friendly name must not be empty")),
+        false => Ok(friendly_name),
+    }
+}
+
+fn to_status(err: DeviceDatabaseError) -> Status {
+    match err {
+        DeviceDatabaseError::UnknownDevice(_) => Status::not_found(err.to_string()),
+        DeviceDatabaseError::DeviceExists(_) => Status::already_exists(err.to_string()),
+        DeviceDatabaseError::FriendlyNameTaken(_) => Status::already_exists(err.to_string()),
+        DeviceDatabaseError::UnsupportedModel(_)
+        | DeviceDatabaseError::UnsupportedLocation(_)
+        | DeviceDatabaseError::InvalidSelector(_)
+        | DeviceDatabaseError::InvalidExpression(_) => Status::invalid_argument(err.to_string()),
+        DeviceDatabaseError::InvalidVirtualDevice(_) => Status::failed_precondition(err.to_string()),
+        DeviceDatabaseError::NoDevicesFile => Status::failed_precondition(err.to_string()),
+        DeviceDatabaseError::SaveFailed(_) => {
+            error!("Unable to save the devices: {}", err);
+            Status::internal(err.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const DEVICES: &str = r#"
+        [GVH5075_0001]
+        friendly_name = "Living Room"
+        room = "living room"
+        tags = ["indoor"]
+
+        [GVH5075_0002]
+        friendly_name = "Freezer"
+        room = "kitchen"
+        tags = ["indoor", "freezer"]
+        calibration = { temperature_offset_in_c = -0.5 }
+
+        [GVH5075_0003]
+        friendly_name = "Fridge"
+        room = "kitchen"
+        tags = ["indoor"]
+
+        [GVH5075_0004]
+        friendly_name = "Garden"
+        floor = 0
+        location = "outdoor"
+        notes = "under the eaves, out of direct sunlight"
+    "#;
+
+    fn list(device_database: Arc<DeviceDatabase>, request: ListDevicesRequest) -> (Vec<String>, String) {
+        let collector = Collector::new(Arc::clone(&device_database), Duration::from_secs(60), Duration::from_secs(60 * 60));
+        let response = list_devices(&collector, &device_database, request).unwrap();
+        (response.devices.into_iter().map(|device| device.unique_id).collect(), response.next_page_token)
+    }
+
+    #[test]
+    fn test_devices_are_filtered_by_room_and_tags() {
+        let device_database = Arc::new(DeviceDatabase::parse(DEVICES));
+        let kitchen = ListDevicesRequest { room: Some("kitchen".to_string()), ..Default::default() };
+        assert_eq!(list(Arc::clone(&device_database), kitchen).0, vec!["GVH5075_0002", "GVH5075_0003"]);
+        let indoor_freezers = ListDevicesRequest {
+            tags: vec!["indoor".to_string(), "freezer".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(list(Arc::clone(&device_database), indoor_freezers).0, vec!["GVH5075_0002"]);
+        let outdoor = ListDevicesRequest { selectors: vec!["location=outdoor".to_string()], ..Default::default() };
+        assert_eq!(list(Arc::clone(&device_database), outdoor).0, vec!["GVH5075_0004"]);
+        assert_eq!(list(device_database, ListDevicesRequest::default()).0.len(), 4);
+    }
+
+    #[test]
+    fn test_devices_are_paginated() {
+        let device_database = Arc::new(DeviceDatabase::parse(DEVICES));
+        let first_page = ListDevicesRequest { page_size: 3, ..Default::default() };
+        let (unique_ids, page_token) = list(Arc::clone(&device_database), first_page);
+        assert_eq!(unique_ids, vec!["GVH5075_0001", "GVH5075_0002", "GVH5075_0003"]);
+        assert_eq!(page_token, "GVH5075_0003");
+        let second_page = ListDevicesRequest { page_size: 3, page_token, ..Default::default() };
+        assert_eq!(list(device_database, second_page), (vec!["GVH5075_0004".to_string()], String::new()));
+    }
+
+    #[test]
+    fn test_device_metadata() {
+        let device_database = DeviceDatabase::parse(DEVICES);
+        let collector = Collector::new(Arc::new(DeviceDatabase::default()), Duration::from_secs(60), Duration::from_secs(60 * 60));
+        let device = get_device(&collector, &device_database, "GVH5075_0002").unwrap();
+        assert_eq!(device.friendly_name, "Freezer");
+        assert_eq!(device.model, "H5075");
+        assert_eq!(device.calibration, Some(Calibration { temperature_offset_in_c: -0.5, humidity_offset: 0.0 }));
+        assert!(!device.online);
+        let garden = get_device(&collector, &device_database, "GVH5075_0004").unwrap();
+        assert_eq!((garden.floor, garden.location.as_deref()), (Some(0), Some("outdoor")));
+        assert_eq!(get_device(&collector, &device_database, "typo").unwrap_err().code(), tonic::Code::NotFound);
+    }
+}
@@ -0,0 +1,139 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use tokio::sync::mpsc;
+use tokio::task::spawn_blocking;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::Status;
+
+use crate::device_database::DeviceDatabase;
+use crate::history::{Bucket, History, HistoryQuery, Summary};
+
+use super::govee_collector::{AggregatedValue, Aggregation, HistoryBucket, QueryHistoryRequest, QueryHistoryResponse};
+use super::utils::{DeviceSelection, to_unix_millis};
+
+/// How many buckets are sent at most in a single response.
+const CHUNK_SIZE: usize = 1000;
+
+/// How many buckets the series of a single device can have at most.
+const MAX_BUCKETS: u64 = 100_000;
+
+/// How many chunks can be waiting for a slow client, before the query waits for it to catch up.
+const CHUNKS_CAPACITY: usize = 4;
+
+pub type HistoryStream = ReceiverStream<Result<QueryHistoryResponse, Status>>;
+
+/// Streams the series of every selected device, one device after another, in chunks of buckets.
+///
+/// Every device gets at least one chunk, even if there is nothing in its history.
+pub fn query_history(
+    history: Option<&Arc<History>>,
+    device_database: &DeviceDatabase,
+    request: QueryHistoryRequest,
+) -> Result<HistoryStream, Status> {
+    let history = Arc::clone(history.ok_or_else(|| Status::failed_precondition("the history is disabled"))?);
+    let aggregations = to_aggregations(&request.aggregations)?;
+    let start = request.start_timestamp;
+    let end = match request.end_timestamp {
+        Some(end) => end,
+        None => to_unix_millis(SystemTime::now()).unwrap_or_default(),
+    };
+    if end <= start {
+        return Err(Status::invalid_argument("time range must end after it starts"));
+    }
+    if request.bucket_size_in_secs == 0 {
+        return Err(Status::invalid_argument("bucket size must be at least 1 second"));
+    }
+    let bucket_ms = request.bucket_size_in_secs as u64 * 1000;
+    if (end - start).div_ceil(bucket_ms) > MAX_BUCKETS {
+        return Err(Status::invalid_argument(format!("time range must fit in at most {} buckets", MAX_BUCKETS)));
+    }
+    let selection = DeviceSelection::new(device_database, request.unique_ids, request.selectors, false)?;
+    let unique_ids = selection.resolve(device_database);
+    let (sender, receiver) = mpsc::channel(CHUNKS_CAPACITY);
+    spawn_blocking(move || {
+        for unique_id in unique_ids {
+            let query = HistoryQuery {
+                device: unique_id.clone(),
+                start_ms: start as i64,
+                end_ms: end as i64,
+                bucket_ms: bucket_ms as i64,
+            };
+            let buckets = match history.query(&query) {
+                Ok(buckets) => buckets,
+                Err(err) => {
+                    error!("Unable to query the history of {}: {}", unique_id, err);
+                    let _ = sender.blocking_send(Err(Status::internal("unable to query the history")));
+                    return;
+                }
+            };
+            let chunks: Vec<&[Bucket]> = match buckets.is_empty() {
+                true => vec![&[]],
+                false => buckets.chunks(CHUNK_SIZE).collect(),
+            };
+            for chunk in chunks {
+                let response = QueryHistoryResponse {
+                    unique_id: unique_id.clone(),
+                    buckets: chunk.iter().map(|bucket| to_history_bucket(bucket, &aggregations)).collect(),
+                };
+                if sender.blocking_send(Ok(response)).is_err() {
+                    debug!("Client disconnected in the middle of a history query");
+                    return;
+                }
+            }
+        }
+    });
+    Ok(ReceiverStream::new(receiver))
+}
+
+fn to_aggregations(aggregations: &[i32]) -> Result<HashSet<Aggregation>, Status> {
+    if aggregations.is_empty() {
+        return Ok([Aggregation::Mean, Aggregation::Min, Aggregation::Max, Aggregation::Last, Aggregation::Count].into());
+    }
+    aggregations.iter()
+        .map(|&aggregation| Aggregation::from_i32(aggregation)
+            .ok_or_else(|| Status::invalid_argument(format!("unknown aggregation {}", aggregation))))
+        .collect()
+}
+
+fn to_history_bucket(bucket: &Bucket, aggregations: &HashSet<Aggregation>) -> HistoryBucket {
+    HistoryBucket {
+        timestamp: bucket.start_ms as u64,
+        count: aggregations.contains(&Aggregation::Count).then_some(bucket.count as u32),
+        temperature_in_c: bucket.temperature_in_c.map(|summary| to_aggregated_value(summary, aggregations)),
+        humidity: bucket.humidity.map(|summary| to_aggregated_value(summary, aggregations)),
+        battery: bucket.battery.map(|summary| to_aggregated_value(summary, aggregations)),
+    }
+}
+
+fn to_aggregated_value(summary: Summary, aggregations: &HashSet<Aggregation>) -> AggregatedValue {
+    let value = |aggregation, value| aggregations.contains(&aggregation).then_some(value);
+    AggregatedValue {
+        mean: value(Aggregation::Mean, summary.mean()),
+        min: value(Aggregation::Min, summary.min),
+        max: value(Aggregation::Max, summary.max),
+        last: value(Aggregation::Last, summary.last),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_requested_aggregations_are_set() {
+        let aggregations = to_aggregations(&[Aggregation::Min as i32, Aggregation::Count as i32]).unwrap();
+        let summary = Summary { count: 2, min: 20.0, max: 21.0, sum: 41.0, last: 21.0 };
+        let bucket = Bucket { start_ms: 1_000, count: 2, temperature_in_c: Some(summary), humidity: None, battery: None };
+        assert_eq!(to_history_bucket(&bucket, &aggregations), HistoryBucket {
+            timestamp: 1_000,
+            count: Some(2),
+            temperature_in_c: Some(AggregatedValue { mean: None, min: Some(20.0), max: None, last: None }),
+            humidity: None,
+            battery: None,
+        });
+        assert_eq!(to_aggregations(&[]).unwrap().len(), 5);
+        assert!(to_aggregations(&[42]).is_err());
+    }
+}
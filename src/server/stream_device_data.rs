@@ -1,11 +1,14 @@
-use std::mem;
+use std::collections::{HashMap, HashSet};
 use std::pin::Pin;
 use std::sync::{Arc, Mutex};
-use std::task::{Context, Poll, Waker};
+use std::task::{Context, Poll};
 use std::time::Duration;
 
-use futures::Stream;
-use tokio::time::sleep;
+use futures::{ready, Stream};
+use tokio::sync::broadcast;
+use tokio::time::{sleep, timeout};
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
 use tonic::Status;
 
 use crate::collector::Collector;
@@ -15,81 +18,188 @@ use super::govee_collector::{
     DeviceData,
     StreamDeviceDataResponse,
 };
-use super::utils::extract_device_data;
+use super::utils::{DeviceSelection, extract_device_data};
 
-struct SharedState {
-    is_working: bool,
-    did_prepare_any_data: bool,
-    device_data: Option<Vec<DeviceData>>,
-    waker: Option<Waker>,
-}
+// clients that fall behind skip straight to the latest snapshot
+const SNAPSHOTS_CAPACITY: usize = 2;
 
-pub struct DeviceDataStream {
+type Snapshot = Arc<Vec<DeviceData>>;
+
+/// Clients requesting the same devices at the same interval share their snapshots.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct SnapshotKey {
+    selection: DeviceSelection,
     refresh_interval: Duration,
+}
+
+type Producers = Arc<Mutex<HashMap<SnapshotKey, broadcast::Sender<Snapshot>>>>;
+
+/// Runs a single producer per distinct periodic stream request and fans its snapshots out to all the clients.
+pub struct SnapshotHub {
     collector: Arc<Collector>,
     device_database: Arc<DeviceDatabase>,
-    unique_ids: Arc<Vec<String>>,
-    shared_state: Arc<Mutex<SharedState>>,
+    producers: Producers,
+}
+
+impl SnapshotHub {
+    pub fn new(collector: Arc<Collector>, device_database: Arc<DeviceDatabase>) -> Self {
+        SnapshotHub { collector, device_database, producers: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    pub async fn subscribe(&self, selection: DeviceSelection, refresh_interval: Duration) -> DeviceDataStream {
+        let key = SnapshotKey { selection, refresh_interval };
+        let snapshots = {
+            let mut producers = self.producers.lock().expect("Could not lock mutex");
+            match producers.get(&key) {
+                Some(sender) => sender.subscribe(),
+                None => {
+                    debug!("Starting a producer for {:?}", key);
+                    let (sender, receiver) = broadcast::channel(SNAPSHOTS_CAPACITY);
+                    producers.insert(key.clone(), sender.clone());
+                    tokio::spawn(produce_snapshots(
+                        key.clone(),
+                        sender,
+                        Arc::clone(&self.collector),
+                        Arc::clone(&self.device_database),
+                        Arc::clone(&self.producers),
+                    ));
+                    receiver
+                }
+            }
+        };
+        // new clients get a fresh snapshot right away, and the shared ones after that
+        let unique_ids = key.selection.resolve(&self.device_database);
+        let initial = extract_device_data(&self.collector, &self.device_database, &unique_ids, key.selection.includes_unknown());
+        DeviceDataStream { initial: Some(initial), snapshots: BroadcastStream::new(snapshots) }
+    }
+
+    #[cfg(test)]
+    fn producer_count(&self) -> usize {
+        self.producers.lock().expect("Could not lock mutex").len()
+    }
+}
+
+async fn produce_snapshots(
+    key: SnapshotKey,
+    sender: broadcast::Sender<Snapshot>,
+    collector: Arc<Collector>,
+    device_database: Arc<DeviceDatabase>,
+    producers: Producers,
+) {
+    let mut missing_unique_ids = HashSet::new();
+    for local_name in key.selection.resolve(&device_database) {
+        if collector.get_latest_reading(&local_name).is_none() {
+            missing_unique_ids.insert(local_name);
+        }
+    }
+    loop {
+        wait_for_refresh(&collector, &missing_unique_ids, key.refresh_interval).await;
+        {
+            // clients subscribe under the same lock, so none of them can be left without a producer
+            let mut producers = producers.lock().expect("Could not lock mutex");
+            if sender.receiver_count() == 0 {
+                debug!("Stopping the producer for {:?}", key);
+                producers.remove(&key);
+                return;
+            }
+        }
+        // the devices might have changed since the last snapshot
+        let unique_ids = key.selection.resolve(&device_database);
+        let device_data = extract_device_data(&collector, &device_database, &unique_ids, key.selection.includes_unknown());
+        missing_unique_ids = device_data.iter()
+            .filter(|device| device.last_update_timestamp.is_none())
+            .map(|device| device.unique_id.clone())
+            .collect();
+        // every client might have disconnected in the meantime, which is fine
+        let _ = sender.send(Arc::new(device_data));
+    }
 }
 
-impl DeviceDataStream {
-    pub fn new(
-        refresh_interval: Duration,
-        collector: Arc<Collector>,
-        device_database: Arc<DeviceDatabase>,
-        unique_ids: Arc<Vec<String>>,
-    ) -> Self {
-        let shared_state = Arc::new(Mutex::new(SharedState {
-            is_working: false,
-            did_prepare_any_data: false,
-            device_data: None,
-            waker: None,
-        }));
-        DeviceDataStream { refresh_interval, collector, device_database, unique_ids, shared_state }
+/// Waits for the refresh interval to pass, or for any of the devices that had no data yet to receive a reading.
+async fn wait_for_refresh(collector: &Collector, missing_unique_ids: &HashSet<String>, refresh_interval: Duration) {
+    if missing_unique_ids.is_empty() {
+        sleep(refresh_interval).await;
+        return;
+    }
+    // subscribe before checking, so that the reading can't slip in between
+    let mut readings = collector.subscribe_readings("StreamDeviceData");
+    for local_name in missing_unique_ids {
+        if collector.get_latest_reading(local_name).is_some() {
+            return;
+        }
+    }
+    let first_reading = readings.recv_matching(|reading| missing_unique_ids.contains(&reading.local_name));
+    if let Ok(Some(reading)) = timeout(refresh_interval, first_reading).await {
+        debug!("Received first data from {}: {:?}", reading.local_name, reading.data);
     }
 }
 
+pub struct DeviceDataStream {
+    initial: Option<Vec<DeviceData>>,
+    snapshots: BroadcastStream<Snapshot>,
+}
+
 impl Drop for DeviceDataStream {
     fn drop(&mut self) {
         debug!("Client disconnected");
-        self.shared_state.lock().expect("Could not lock mutex").waker = None;
     }
 }
 
 impl Stream for DeviceDataStream {
     type Item = Result<StreamDeviceDataResponse, Status>;
 
-    fn poll_next(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let mut shared_state = self.shared_state.lock().expect("Could not lock mutex");
-        shared_state.waker = Some(ctx.waker().clone());
-        if !shared_state.is_working {
-            shared_state.is_working = true;
-            let did_prepare_any_data = shared_state.did_prepare_any_data;
-            let refresh_interval = self.refresh_interval;
-            let shared_state = Arc::clone( &self.shared_state);
-            let collector = Arc::clone(&self.collector);
-            let device_database = Arc::clone(&self.device_database);
-            let unique_ids = Arc::clone(&self.unique_ids);
-            tokio::spawn(async move {
-                if did_prepare_any_data {
-                    sleep(refresh_interval).await;
+    fn poll_next(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(device_data) = self.initial.take() {
+            return Poll::Ready(Some(Ok(StreamDeviceDataResponse { devices: device_data, is_replay: false })));
+        }
+        loop {
+            match ready!(Pin::new(&mut self.snapshots).poll_next(ctx)) {
+                Some(Ok(snapshot)) => {
+                    let device_data = snapshot.as_ref().clone();
+                    return Poll::Ready(Some(Ok(StreamDeviceDataResponse { devices: device_data, is_replay: false })));
                 }
-                let device_data = extract_device_data(&collector, &device_database, &unique_ids).await;
-                let mut shared_state = shared_state.lock().expect("Could not lock mutex");
-                shared_state.did_prepare_any_data = true;
-                shared_state.device_data = Some(device_data);
-                if let Some(waker) = mem::take(&mut shared_state.waker) {
-                    waker.wake();
+                Some(Err(BroadcastStreamRecvError::Lagged(skipped))) => {
+                    debug!("Client is lagging behind, skipped {} snapshots", skipped);
                 }
-                shared_state.is_working = false;
-            });
-        }
-        match mem::take(&mut shared_state.device_data) {
-            Some(device_data) => {
-                Poll::Ready(Some(Ok(StreamDeviceDataResponse { devices: device_data })))
-            },
-            None => Poll::Pending,
+                None => return Poll::Ready(None),
+            }
         }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+
+    // time only advances while every task is idle, so the producers' ticks can't race the assertions
+    #[tokio::test(start_paused = true)]
+    async fn test_clients_share_producers() {
+        let device_database = Arc::new(DeviceDatabase::default());
+        let collector = Arc::new(Collector::new(Arc::clone(&device_database), Duration::from_secs(60), Duration::from_secs(60 * 60)));
+        let all = DeviceSelection::new(&device_database, vec![], vec![], false).unwrap();
+        let hub = SnapshotHub::new(collector, device_database);
+        let refresh_interval = Duration::from_millis(10);
+
+        let mut first = hub.subscribe(all.clone(), refresh_interval).await;
+        let mut second = hub.subscribe(all.clone(), refresh_interval).await;
+        let third = hub.subscribe(all, Duration::from_millis(20)).await;
+        assert_eq!(hub.producer_count(), 2);
+
+        for stream in [&mut first, &mut second] {
+            // initial snapshot, followed by a shared one
+            assert!(stream.next().await.is_some());
+            assert!(stream.next().await.is_some());
+        }
+
+        // producers stop at their first tick without clients
+        drop(third);
+        sleep(Duration::from_millis(20)).await;
+        assert_eq!(hub.producer_count(), 1);
+        drop(first);
+        drop(second);
+        sleep(refresh_interval * 2).await;
+        assert_eq!(hub.producer_count(), 0);
+    }
+}
@@ -0,0 +1,217 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tokio::time::{Instant, sleep_until};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::Status;
+
+use crate::collector::{Collector, Reading};
+use crate::device_database::DeviceDatabase;
+
+use super::govee_collector::{DeviceData, StreamDeviceDataResponse};
+use super::utils::{DeviceSelection, extract_device_data, replay_readings};
+
+const CHANNEL_CAPACITY: usize = 16;
+// readings have a resolution of 0.1, anything smaller is a floating point error
+const EPSILON: f32 = 1e-3;
+
+/// Decides whether a reading differs enough from the last one sent to the client.
+///
+/// Fields without a deadband are reported on any change, so without any deadbands, only identical readings are left out.
+#[derive(Debug, Default)]
+pub struct ChangeFilter {
+    temperature_deadband: Option<f32>,
+    humidity_deadband: Option<f32>,
+    battery_deadband: Option<f32>,
+    last_sent: HashMap<String, [Option<f32>; 3]>,
+}
+
+impl ChangeFilter {
+    pub fn new(
+        temperature_deadband: Option<f32>,
+        humidity_deadband: Option<f32>,
+        battery_deadband: Option<f32>,
+    ) -> Result<ChangeFilter, Status> {
+        for (name, deadband) in [
+            ("temperature deadband", temperature_deadband),
+            ("humidity deadband", humidity_deadband),
+            ("battery deadband", battery_deadband),
+        ] {
+            if deadband.is_some_and(|deadband| deadband.is_nan() || deadband < 0.0) {
+                return Err(Status::invalid_argument(format!("{} must be a non-negative number", name)));
+            }
+        }
+        Ok(ChangeFilter { temperature_deadband, humidity_deadband, battery_deadband, last_sent: HashMap::new() })
+    }
+
+    fn is_significant(&self, reading: &Reading) -> bool {
+        let deadbands = [self.temperature_deadband, self.humidity_deadband, self.battery_deadband];
+        let values = [reading.data.temperature_in_c(), reading.data.humidity(), reading.data.battery().map(f32::from)];
+        match self.last_sent.get(&reading.local_name) {
+            Some(last_sent) => values.iter().zip(last_sent).zip(deadbands).any(|((value, last_sent), deadband)| {
+                match (value, last_sent) {
+                    (Some(value), Some(last_sent)) => match deadband {
+                        Some(deadband) => (value - last_sent).abs() + EPSILON >= deadband,
+                        None => value != last_sent,
+                    },
+                    // a value appearing (or disappearing) is always a change
+                    (value, last_sent) => value != last_sent,
+                }
+            }),
+            None => true,
+        }
+    }
+
+    fn remember(&mut self, devices: &[DeviceData]) {
+        for device in devices {
+            let values = [device.temperature_in_c, device.humidity, device.battery];
+            self.last_sent.insert(device.unique_id.clone(), values);
+        }
+    }
+}
+
+/// Streams the requested devices as soon as they receive new readings, rather than on a fixed schedule.
+pub fn stream_device_data_changes(
+    collector: Arc<Collector>,
+    device_database: Arc<DeviceDatabase>,
+    selection: DeviceSelection,
+    min_interval: Duration,
+    filter: ChangeFilter,
+    resume_from: Option<u64>,
+) -> ReceiverStream<Result<StreamDeviceDataResponse, Status>> {
+    let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+    tokio::spawn(async move {
+        produce_changes(sender, collector, device_database, selection, min_interval, filter, resume_from).await;
+        debug!("Client disconnected");
+    });
+    ReceiverStream::new(receiver)
+}
+
+async fn produce_changes(
+    sender: mpsc::Sender<Result<StreamDeviceDataResponse, Status>>,
+    collector: Arc<Collector>,
+    device_database: Arc<DeviceDatabase>,
+    selection: DeviceSelection,
+    min_interval: Duration,
+    mut filter: ChangeFilter,
+    resume_from: Option<u64>,
+) {
+    // subscribe before taking the initial snapshot, so that no reading can slip in between
+    let mut readings = collector.subscribe_readings("StreamDeviceData");
+    let unique_ids = selection.resolve(&device_database);
+    let replayed = match resume_from {
+        Some(sequence_number) => replay_readings(&collector, &device_database, &unique_ids, sequence_number),
+        None => None,
+    };
+    let mut last_replayed = None;
+    match replayed {
+        Some(responses) => {
+            debug!("Replaying {} readings after {:?}", responses.len(), resume_from);
+            for response in responses {
+                last_replayed = response.devices.iter().filter_map(|device| device.sequence_number).max();
+                filter.remember(&response.devices);
+                if sender.send(Ok(response)).await.is_err() {
+                    return;
+                }
+            }
+        }
+        None => {
+            if let Some(sequence_number) = resume_from {
+                info!("Unable to resume from reading {}, sending a snapshot instead", sequence_number);
+            }
+            let devices = extract_device_data(&collector, &device_database, &unique_ids, selection.includes_unknown());
+            filter.remember(&devices);
+            if sender.send(Ok(StreamDeviceDataResponse { devices, is_replay: false })).await.is_err() {
+                return;
+            }
+        }
+    }
+    let mut last_sent = Instant::now();
+    let mut pending = HashSet::new();
+    loop {
+        tokio::select! {
+            _ = sender.closed() => return,
+            reading = readings.recv() => match reading {
+                Some(reading) => {
+                    let was_replayed = last_replayed.is_some_and(|last| reading.sequence_number <= last);
+                    let is_requested = selection.contains(&device_database, &reading.local_name);
+                    if !was_replayed && is_requested && filter.is_significant(&reading) {
+                        pending.insert(reading.local_name);
+                    }
+                }
+                None => return,
+            },
+            _ = sleep_until(last_sent + min_interval), if !pending.is_empty() => {}
+        }
+        if pending.is_empty() || last_sent.elapsed() < min_interval {
+            continue;
+        }
+        // keep the order of the request
+        let changed: Vec<String> = selection.resolve(&device_database).into_iter()
+            .filter(|id| pending.contains(id))
+            .collect();
+        pending.clear();
+        let devices = extract_device_data(&collector, &device_database, &changed, selection.includes_unknown());
+        filter.remember(&devices);
+        if sender.send(Ok(StreamDeviceDataResponse { devices, is_replay: false })).await.is_err() {
+            return;
+        }
+        last_sent = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(unique_id: &str, temperature_in_c: f32, humidity: f32) -> DeviceData {
+        DeviceData {
+            unique_id: unique_id.to_string(),
+            temperature_in_c: Some(temperature_in_c),
+            humidity: Some(humidity),
+            battery: Some(100.0),
+            ..Default::default()
+        }
+    }
+
+    fn reading(local_name: &str, temperature_in_c: f32, humidity: f32) -> Reading {
+        Reading::h5075(local_name, temperature_in_c, humidity, 100)
+    }
+
+    #[test]
+    fn test_any_change_is_significant_without_deadbands() {
+        let mut filter = ChangeFilter::default();
+        filter.remember(&[device("A", 21.0, 40.0)]);
+        assert!(!filter.is_significant(&reading("A", 21.0, 40.0)));
+        assert!(filter.is_significant(&reading("A", 21.1, 40.0)));
+        assert!(filter.is_significant(&reading("B", 21.0, 40.0)));
+    }
+
+    #[test]
+    fn test_deadbands_filter_out_noise() {
+        let mut filter = ChangeFilter::new(Some(0.2), Some(1.0), Some(5.0)).unwrap();
+        assert!(filter.is_significant(&reading("A", 21.0, 40.0)));
+        filter.remember(&[device("A", 21.0, 40.0)]);
+        assert!(!filter.is_significant(&reading("A", 21.1, 40.5)));
+        assert!(filter.is_significant(&reading("A", 21.2, 40.0)));
+        assert!(filter.is_significant(&reading("A", 20.8, 40.0)));
+        assert!(filter.is_significant(&reading("A", 21.0, 41.0)));
+    }
+
+    #[test]
+    fn test_fields_without_deadband_report_any_change() {
+        let mut filter = ChangeFilter::new(Some(0.5), None, None).unwrap();
+        filter.remember(&[device("A", 21.0, 40.0)]);
+        assert!(!filter.is_significant(&reading("A", 21.3, 40.0)));
+        assert!(filter.is_significant(&reading("A", 21.0, 40.1)));
+    }
+
+    #[test]
+    fn test_invalid_deadbands_are_rejected() {
+        assert!(ChangeFilter::new(Some(0.0), None, None).is_ok());
+        assert_eq!(ChangeFilter::new(Some(-0.5), None, None).unwrap_err().code(), tonic::Code::InvalidArgument);
+        assert_eq!(ChangeFilter::new(None, Some(f32::NAN), None).unwrap_err().code(), tonic::Code::InvalidArgument);
+    }
+}
@@ -0,0 +1,62 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::{ready, Stream};
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tonic::Status;
+
+use crate::collector::LivenessChange;
+use crate::device_database::DeviceDatabase;
+
+use super::govee_collector::DeviceStatusChange;
+use super::utils::{DeviceSelection, to_unix_millis};
+
+pub struct DeviceStatusStream {
+    changes: BroadcastStream<LivenessChange>,
+    device_database: Arc<DeviceDatabase>,
+    selection: DeviceSelection,
+}
+
+impl DeviceStatusStream {
+    pub fn new(
+        changes: broadcast::Receiver<LivenessChange>,
+        device_database: Arc<DeviceDatabase>,
+        selection: DeviceSelection,
+    ) -> Self {
+        DeviceStatusStream {
+            changes: BroadcastStream::new(changes),
+            device_database,
+            selection,
+        }
+    }
+}
+
+impl Stream for DeviceStatusStream {
+    type Item = Result<DeviceStatusChange, Status>;
+
+    fn poll_next(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match ready!(Pin::new(&mut self.changes).poll_next(ctx)) {
+                Some(Ok(change)) if self.selection.contains(&self.device_database, &change.local_name) => {
+                    let friendly_name = self.device_database.get_friendly_name(&change.local_name)
+                        .unwrap_or_default();
+                    return Poll::Ready(Some(Ok(DeviceStatusChange {
+                        unique_id: change.local_name,
+                        friendly_name,
+                        online: change.online,
+                        last_seen_timestamp: to_unix_millis(change.last_seen).unwrap_or_default(),
+                        timestamp: to_unix_millis(change.timestamp).unwrap_or_default(),
+                    })));
+                }
+                Some(Ok(_)) => {}
+                Some(Err(BroadcastStreamRecvError::Lagged(skipped))) => {
+                    warn!("Client is lagging behind, skipped {} status changes", skipped);
+                }
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
@@ -1,32 +1,33 @@
-use std::time::{UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::collector::Collector;
-use crate::device_database::DeviceDatabase;
+use tonic::Status;
 
-use super::govee_collector::DeviceData;
+use crate::collector::{self, Collector, Reading, WindowStatistics};
+use crate::device_database::{DeviceDatabase, Selector};
 
-pub async fn extract_device_data(
+use super::govee_collector::{DeviceData, DeviceDiagnostics, Extremes, LinkQuality, RecentReading, StreamDeviceDataResponse};
+
+const DEFAULT_REFRESH_INTERVAL_IN_SECS: u32 = 60;
+const MAX_REFRESH_INTERVAL_IN_SECS: u32 = 24 * 60 * 60;
+
+/// Unknown devices are only reported (as such) if the selection includes them, as devices that were removed
+/// after the selection was resolved are left out otherwise.
+pub fn extract_device_data(
     collector: &Collector,
     device_database: &DeviceDatabase,
     unique_ids: &Vec<String>,
+    include_unknown: bool,
 ) -> Vec<DeviceData> {
+    // all the devices are taken from the same snapshot, even if readings arrive in the meantime
+    let readings = collector.get_latest_readings();
     let mut devices = vec![];
     for local_name in unique_ids {
-        if let Some(device_data) = collector.get_latest_device_data(local_name).await {
-            let friendly_name = device_database.get_friendly_name(local_name).unwrap().clone();
-            let last_update_timestamp = device_data.last_update_timestamp()
-                .duration_since(UNIX_EPOCH)
-                .ok()
-                .and_then(|d| d.as_millis().try_into().ok());
-            devices.push(DeviceData {
-                unique_id: local_name.clone(),
-                friendly_name,
-                temperature_in_c: Some(device_data.temperature_in_c()),
-                humidity: Some(device_data.humidity()),
-                battery: Some(device_data.battery() as f32),
-                last_update_timestamp,
-            })
+        let device_data = readings.get(local_name)
+            .and_then(|reading| reading_to_device_data(collector, device_database, reading));
+        if let Some(device_data) = device_data {
+            devices.push(device_data)
         } else if let Some(friendly_name) = device_database.get_friendly_name(local_name) {
+            let liveness = collector.get_liveness(local_name);
             devices.push(DeviceData {
                 unique_id: local_name.clone(),
                 friendly_name: friendly_name.clone(),
@@ -34,15 +35,387 @@ pub async fn extract_device_data(
                 humidity: None,
                 battery: None,
                 last_update_timestamp: None,
+                online: liveness.online,
+                last_seen_timestamp: liveness.last_seen.and_then(to_unix_millis),
+                age_in_secs: None,
+                sequence_number: None,
+                is_unknown: false,
+                recent_readings: vec![],
+                extremes: None,
+                is_restored: false,
+            })
+        } else if include_unknown {
+            devices.push(DeviceData { unique_id: local_name.clone(), is_unknown: true, ..Default::default() })
+        }
+    }
+    devices
+}
+
+pub fn reading_to_device_data(
+    collector: &Collector,
+    device_database: &DeviceDatabase,
+    reading: &Reading,
+) -> Option<DeviceData> {
+    let local_name = &reading.local_name;
+    let friendly_name = device_database.get_friendly_name(local_name)?;
+    let liveness = collector.get_liveness(local_name);
+    let last_update_timestamp = to_unix_millis(reading.data.last_update_timestamp());
+    let age_in_secs = reading.data.last_update_timestamp()
+        .elapsed()
+        .ok()
+        .and_then(|d| d.as_secs().try_into().ok());
+    Some(DeviceData {
+        unique_id: local_name.clone(),
+        friendly_name,
+        temperature_in_c: reading.data.temperature_in_c(),
+        humidity: reading.data.humidity(),
+        battery: reading.data.battery().map(f32::from),
+        last_update_timestamp,
+        online: liveness.online,
+        last_seen_timestamp: liveness.last_seen.and_then(to_unix_millis),
+        age_in_secs,
+        // restored readings can't be resumed from
+        sequence_number: (!reading.restored).then_some(reading.sequence_number),
+        is_unknown: false,
+        recent_readings: vec![],
+        extremes: None,
+        is_restored: reading.restored,
+    })
+}
+
+/// Adds the readings kept in memory within the given windows to the known devices.
+pub fn add_recent_readings(
+    collector: &Collector,
+    devices: &mut [DeviceData],
+    recent_readings_window: Option<Duration>,
+    extremes_window: Option<Duration>,
+) {
+    for device in devices.iter_mut().filter(|device| !device.is_unknown) {
+        if let Some(window) = recent_readings_window {
+            device.recent_readings = collector.get_recent_readings(&device.unique_id, window).iter()
+                .map(to_recent_reading)
+                .collect();
+        }
+        if let Some(window) = extremes_window {
+            device.extremes = Some(to_extremes(collector.get_extremes(&device.unique_id, window)));
+        }
+    }
+}
+
+fn to_recent_reading(reading: &collector::RecentReading) -> RecentReading {
+    RecentReading {
+        timestamp: to_unix_millis(reading.timestamp).unwrap_or_default(),
+        temperature_in_c: reading.temperature_in_c,
+        humidity: reading.humidity,
+    }
+}
+
+fn to_extremes(extremes: collector::Extremes) -> Extremes {
+    Extremes {
+        min_temperature_in_c: extremes.min_temperature_in_c,
+        max_temperature_in_c: extremes.max_temperature_in_c,
+        min_humidity: extremes.min_humidity,
+        max_humidity: extremes.max_humidity,
+    }
+}
+
+/// Prepares stream responses for the readings of the given devices that came after the given sequence number,
+/// or returns `None` if some of those readings are no longer available.
+pub fn replay_readings(
+    collector: &Collector,
+    device_database: &DeviceDatabase,
+    unique_ids: &[String],
+    sequence_number: u64,
+) -> Option<Vec<StreamDeviceDataResponse>> {
+    let readings = collector.get_readings_since(sequence_number)?;
+    let mut responses = vec![];
+    for reading in readings.iter().filter(|reading| unique_ids.contains(&reading.local_name)) {
+        if let Some(device_data) = reading_to_device_data(collector, device_database, reading) {
+            responses.push(StreamDeviceDataResponse { devices: vec![device_data], is_replay: true });
+        }
+    }
+    Some(responses)
+}
+
+pub async fn extract_diagnostics(
+    collector: &Collector,
+    device_database: &DeviceDatabase,
+    unique_ids: &Vec<String>,
+) -> Vec<DeviceDiagnostics> {
+    let mut devices = vec![];
+    for local_name in unique_ids {
+        if let Some(friendly_name) = device_database.get_friendly_name(local_name) {
+            let summary = collector.get_link_summary(local_name).await;
+            devices.push(DeviceDiagnostics {
+                unique_id: local_name.clone(),
+                friendly_name: friendly_name.clone(),
+                advertisements_received: summary.as_ref().map_or(0, |s| s.advertisements_received),
+                decode_failures: summary.as_ref().map_or(0, |s| s.decode_failures),
+                expected_interval_in_secs: summary.as_ref().map_or(0.0, |s| s.expected_interval.as_secs_f32()),
+                link_quality: summary.map_or(vec![], |s| s.windows.iter().map(to_link_quality).collect()),
             })
         }
     }
     devices
 }
 
-pub fn resolve_unique_ids(device_database: &DeviceDatabase, input: Vec<String>) -> Vec<String> {
-    match input.is_empty() {
-        false => input,
-        true => device_database.get_all_devices().into_iter().map(|s| s.clone()).collect(),
+fn to_link_quality(window: &WindowStatistics) -> LinkQuality {
+    LinkQuality {
+        window_in_secs: window.window.as_secs() as u32,
+        advertisements_received: window.advertisements_received as u32,
+        min_interval_in_secs: window.intervals.map(|d| d.min.as_secs_f32()),
+        mean_interval_in_secs: window.intervals.map(|d| d.mean),
+        p90_interval_in_secs: window.interval_p90.map(|d| d.as_secs_f32()),
+        max_interval_in_secs: window.intervals.map(|d| d.max.as_secs_f32()),
+        estimated_packet_loss: window.estimated_packet_loss,
+        min_rssi: window.rssi.map(|d| d.min as i32),
+        mean_rssi: window.rssi.map(|d| d.mean),
+        max_rssi: window.rssi.map(|d| d.max as i32),
+    }
+}
+
+/// The devices a request is about.
+///
+/// It's resolved every time it's used, so that long-running streams follow changes to the configuration.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DeviceSelection {
+    // all the devices, if there are neither ids nor selectors
+    unique_ids: Vec<String>,
+    // devices matching all of them are selected along with the requested ones
+    selectors: Vec<Selector>,
+    include_unknown: bool,
+}
+
+impl DeviceSelection {
+    /// Selects all the devices when none are requested.
+    ///
+    /// Unknown devices are rejected with `NOT_FOUND` listing all of them,
+    /// unless the client asked for them to be reported alongside the known ones.
+    /// Selectors that don't match any device are fine, as devices might be configured later.
+    pub fn new(
+        device_database: &DeviceDatabase,
+        unique_ids: Vec<String>,
+        selectors: Vec<String>,
+        lenient: bool,
+    ) -> Result<Self, Status> {
+        let selectors = selectors.iter()
+            .map(|selector| selector.parse())
+            .collect::<Result<Vec<Selector>, _>>()
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        let unknown: Vec<&str> = unique_ids.iter()
+            .filter(|local_name| !device_database.contains_device(local_name))
+            .map(String::as_str)
+            .collect();
+        if !unknown.is_empty() && !lenient {
+            return Err(Status::not_found(format!("unknown devices: {}", unknown.join(", "))));
+        }
+        Ok(DeviceSelection { unique_ids, selectors, include_unknown: lenient })
+    }
+
+    /// Returns the selected devices, in the order they were requested in (or ordered by their ids),
+    /// followed by the ones matching the selectors (ordered by their ids).
+    pub fn resolve(&self, device_database: &DeviceDatabase) -> Vec<String> {
+        let devices = device_database.get_all_devices();
+        if self.unique_ids.is_empty() && self.selectors.is_empty() {
+            let mut unique_ids: Vec<String> = devices.keys().cloned().collect();
+            unique_ids.sort();
+            return unique_ids;
+        }
+        let mut unique_ids: Vec<String> = self.unique_ids.iter()
+            .filter(|local_name| self.include_unknown || devices.contains_key(*local_name))
+            .cloned()
+            .collect();
+        if !self.selectors.is_empty() {
+            let mut selected: Vec<String> = devices.iter()
+                .filter(|(local_name, device)| {
+                    !self.unique_ids.contains(local_name) && self.selectors.iter().all(|selector| selector.matches(device))
+                })
+                .map(|(local_name, _)| local_name.clone())
+                .collect();
+            selected.sort();
+            unique_ids.extend(selected);
+        }
+        unique_ids
+    }
+
+    /// Whether unknown devices are reported alongside the known ones.
+    pub fn includes_unknown(&self) -> bool {
+        self.include_unknown
+    }
+
+    pub fn contains(&self, device_database: &DeviceDatabase, local_name: &str) -> bool {
+        let devices = device_database.get_all_devices();
+        let device = match devices.get(local_name) {
+            Some(device) => device,
+            None => return false,
+        };
+        let selects_all = self.unique_ids.is_empty() && self.selectors.is_empty();
+        let is_requested = self.unique_ids.iter().any(|unique_id| unique_id == local_name);
+        let is_selected = !self.selectors.is_empty() && self.selectors.iter().all(|selector| selector.matches(device));
+        selects_all || is_requested || is_selected
+    }
+}
+
+pub fn to_refresh_interval(refresh_interval_in_secs: Option<u32>) -> Result<Duration, Status> {
+    match refresh_interval_in_secs.unwrap_or(DEFAULT_REFRESH_INTERVAL_IN_SECS) {
+        0 => Err(Status::invalid_argument("refresh interval must be at least 1 second")),
+        secs if secs > MAX_REFRESH_INTERVAL_IN_SECS => Err(Status::invalid_argument(format!(
+            "refresh interval must be at most {} seconds", MAX_REFRESH_INTERVAL_IN_SECS
+        ))),
+        secs => Ok(Duration::from_secs(secs as u64)),
+    }
+}
+
+/// Checks that a window fits within the recent readings the collector keeps.
+pub fn to_recent_window(window_in_secs: Option<u32>, kept: Duration, name: &str) -> Result<Option<Duration>, Status> {
+    match window_in_secs {
+        None => Ok(None),
+        Some(0) => Err(Status::invalid_argument(format!("{} must be at least 1 second", name))),
+        Some(secs) if Duration::from_secs(secs as u64) > kept => Err(Status::invalid_argument(format!(
+            "{} must be at most {} seconds, as no older readings are kept", name, kept.as_secs()
+        ))),
+        Some(secs) => Ok(Some(Duration::from_secs(secs as u64))),
+    }
+}
+
+pub fn to_unix_millis(timestamp: SystemTime) -> Option<u64> {
+    timestamp.duration_since(UNIX_EPOCH)
+        .ok()
+        .and_then(|d| d.as_millis().try_into().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tonic::Code;
+
+    use super::*;
+
+    fn device_database() -> DeviceDatabase {
+        let mut device_database = DeviceDatabase::default();
+        device_database.add_device("GVH5075_6A19".to_string(), "Living Room".to_string());
+        device_database
+    }
+
+    #[test]
+    fn test_restored_readings_cannot_be_resumed_from() {
+        let device_database = Arc::new(device_database());
+        let collector = Collector::new(Arc::clone(&device_database), Duration::from_secs(60), Duration::from_secs(60 * 60));
+        let state: collector::SavedState = serde_json::from_str(r#"{
+            "version": 1,
+            "known_devices": {"A4:C1:38:00:6A:19": "GVH5075_6A19", "A4:C1:38:00:00:00": "GVH5075_0000"},
+            "readings": {
+                "GVH5075_6A19": {"timestamp_ms": 1600000000000, "temperature_in_c": 21.5, "humidity": 40.0, "battery": 100},
+                "GVH5075_0000": {"timestamp_ms": 1600000000000, "temperature_in_c": -18.0, "humidity": 70.0, "battery": 90}
+            }
+        }"#).unwrap();
+        collector.restore(state);
+        // the second device is no longer configured
+        assert!(collector.get_latest_reading("GVH5075_0000").is_none());
+        assert_eq!(collector.get_address("GVH5075_0000"), None);
+        assert!(collector.get_latest_reading("GVH5075_6A19").unwrap().restored);
+
+        let unique_ids = vec!["GVH5075_6A19".to_string(), "GVH5075_0000".to_string()];
+        let devices = extract_device_data(&collector, &device_database, &unique_ids, false);
+        assert_eq!(devices.len(), 1);
+        assert_eq!((devices[0].temperature_in_c, devices[0].battery), (Some(21.5), Some(100.0)));
+        assert_eq!(devices[0].last_update_timestamp, Some(1_600_000_000_000));
+        assert_eq!((devices[0].sequence_number, devices[0].is_restored), (None, true));
+    }
+
+    #[test]
+    fn test_unknown_devices_are_not_found() {
+        let input = vec!["GVH5075_6A19".to_string(), "GVH5075_0000".to_string(), "typo".to_string()];
+        let status = DeviceSelection::new(&device_database(), input, vec![], false).unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+        assert_eq!(status.message(), "unknown devices: GVH5075_0000, typo");
+    }
+
+    #[test]
+    fn test_unknown_devices_are_kept_in_lenient_mode() {
+        let device_database = device_database();
+        let input = vec!["GVH5075_6A19".to_string(), "typo".to_string()];
+        let selection = DeviceSelection::new(&device_database, input.clone(), vec![], true).unwrap();
+        assert_eq!(selection.resolve(&device_database), input);
+        let selection = DeviceSelection::new(&device_database, vec![], vec![], false).unwrap();
+        assert_eq!(selection.resolve(&device_database), vec!["GVH5075_6A19".to_string()]);
+    }
+
+    #[test]
+    fn test_removed_devices_are_left_out_unless_lenient() {
+        let device_database = device_database();
+        let strict = DeviceSelection::new(&device_database, vec!["GVH5075_6A19".to_string()], vec![], false).unwrap();
+        let unique_ids = vec!["GVH5075_6A19".to_string(), "typo".to_string()];
+        let lenient = DeviceSelection::new(&device_database, unique_ids.clone(), vec![], true).unwrap();
+        let resolved = strict.resolve(&device_database);
+        // the device is gone by the time its data is extracted
+        let device_database = DeviceDatabase::default();
+        let collector = Collector::new(Arc::new(DeviceDatabase::default()), Duration::from_secs(60), Duration::from_secs(60 * 60));
+        assert!(extract_device_data(&collector, &device_database, &resolved, strict.includes_unknown()).is_empty());
+        let devices = extract_device_data(&collector, &device_database, &unique_ids, lenient.includes_unknown());
+        assert_eq!(devices.iter().map(|device| device.is_unknown).collect::<Vec<_>>(), vec![true, true]);
+    }
+
+    #[test]
+    fn test_selection_follows_configuration_changes() {
+        let mut device_database = device_database();
+        let all = DeviceSelection::new(&device_database, vec![], vec![], false).unwrap();
+        let living_room = DeviceSelection::new(&device_database, vec!["GVH5075_6A19".to_string()], vec![], false).unwrap();
+        device_database.add_device("GVH5075_A1B2".to_string(), "Garage".to_string());
+        assert_eq!(all.resolve(&device_database), vec!["GVH5075_6A19".to_string(), "GVH5075_A1B2".to_string()]);
+        assert!(all.contains(&device_database, "GVH5075_A1B2"));
+        assert!(!living_room.contains(&device_database, "GVH5075_A1B2"));
+        assert!(!DeviceSelection::new(&device_database, vec![], vec![], false).unwrap().contains(&device_database, "typo"));
+    }
+
+    #[test]
+    fn test_devices_are_selected_by_their_metadata() {
+        let device_database = DeviceDatabase::parse(r#"
+            [GVH5075_0001]
+            friendly_name = "Chest Freezer"
+            room = "basement"
+            tags = ["freezer"]
+
+            [GVH5075_0002]
+            friendly_name = "Workshop"
+            room = "basement"
+
+            [GVH5075_0003]
+            friendly_name = "Fridge Freezer"
+            room = "kitchen"
+            tags = ["freezer"]
+        "#);
+        let select = |unique_ids: &[&str], selectors: &[&str]| DeviceSelection::new(
+            &device_database,
+            unique_ids.iter().map(|unique_id| unique_id.to_string()).collect(),
+            selectors.iter().map(|selector| selector.to_string()).collect(),
+            false,
+        );
+        assert_eq!(select(&[], &["room=basement"]).unwrap().resolve(&device_database), vec!["GVH5075_0001", "GVH5075_0002"]);
+        assert_eq!(select(&[], &["room=basement", "tag=freezer"]).unwrap().resolve(&device_database), vec!["GVH5075_0001"]);
+        let freezers_and_workshop = select(&["GVH5075_0002"], &["tag=freezer"]).unwrap();
+        assert_eq!(freezers_and_workshop.resolve(&device_database), vec!["GVH5075_0002", "GVH5075_0001", "GVH5075_0003"]);
+        assert!(freezers_and_workshop.contains(&device_database, "GVH5075_0003"));
+        assert!(!select(&[], &["room=attic"]).unwrap().contains(&device_database, "GVH5075_0001"));
+        assert_eq!(select(&[], &["colour=blue"]).unwrap_err().code(), Code::InvalidArgument);
+    }
+
+    #[test]
+    fn test_refresh_interval_is_validated() {
+        assert_eq!(to_refresh_interval(None).unwrap(), Duration::from_secs(60));
+        assert_eq!(to_refresh_interval(Some(5)).unwrap(), Duration::from_secs(5));
+        assert_eq!(to_refresh_interval(Some(0)).unwrap_err().code(), Code::InvalidArgument);
+        assert_eq!(to_refresh_interval(Some(24 * 60 * 60 + 1)).unwrap_err().code(), Code::InvalidArgument);
+    }
+
+    #[test]
+    fn test_recent_window_is_validated() {
+        let kept = Duration::from_secs(60 * 60);
+        assert_eq!(to_recent_window(None, kept, "window").unwrap(), None);
+        assert_eq!(to_recent_window(Some(600), kept, "window").unwrap(), Some(Duration::from_secs(600)));
+        assert_eq!(to_recent_window(Some(0), kept, "window").unwrap_err().code(), Code::InvalidArgument);
+        assert_eq!(to_recent_window(Some(60 * 60 + 1), kept, "window").unwrap_err().code(), Code::InvalidArgument);
+        assert_eq!(to_recent_window(Some(1), Duration::ZERO, "window").unwrap_err().code(), Code::InvalidArgument);
     }
 }
@@ -0,0 +1,90 @@
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+
+/// A map that is replaced by an updated copy on every write, instead of being modified in place.
+///
+/// Readers never wait: they either look up a single entry, or take a consistent snapshot of the whole map
+/// which stays valid (and unchanged) for as long as they hold on to it.
+pub struct SnapshotMap<K, V> {
+    current: ArcSwap<HashMap<K, V>>,
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> SnapshotMap<K, V> {
+    pub fn new() -> SnapshotMap<K, V> {
+        SnapshotMap { current: ArcSwap::from_pointee(HashMap::new()) }
+    }
+
+    pub fn snapshot(&self) -> Arc<HashMap<K, V>> {
+        self.current.load_full()
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.current.load().get(key).cloned()
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.current.load().contains_key(key)
+    }
+
+    pub fn replace(&self, map: HashMap<K, V>) {
+        self.current.store(Arc::new(map));
+    }
+
+    /// Applies the update to a copy of the map and publishes it.
+    ///
+    /// Concurrent writers don't lose each other's updates: the update is retried on top of the newer map,
+    /// and only the result of the attempt that got published is returned.
+    pub fn update<R>(&self, mut update: impl FnMut(&mut HashMap<K, V>) -> R) -> R {
+        let mut result = None;
+        self.current.rcu(|current| {
+            let mut updated = HashMap::clone(current);
+            result = Some(update(&mut updated));
+            updated
+        });
+        result.expect("update was never applied")
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> Default for SnapshotMap<K, V> {
+    fn default() -> Self {
+        SnapshotMap::new()
+    }
+}
+
+impl<K, V> From<HashMap<K, V>> for SnapshotMap<K, V> {
+    fn from(map: HashMap<K, V>) -> Self {
+        SnapshotMap { current: ArcSwap::from_pointee(map) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshots_are_not_affected_by_updates() {
+        let map = SnapshotMap::new();
+        map.update(|map| map.insert("A".to_string(), 1));
+        let snapshot = map.snapshot();
+        let previous = map.update(|map| map.insert("A".to_string(), 2));
+        map.update(|map| map.insert("B".to_string(), 3));
+
+        assert_eq!(previous, Some(1));
+        assert_eq!(snapshot.get("A"), Some(&1));
+        assert_eq!(snapshot.get("B"), None);
+        assert_eq!(map.get("A"), Some(2));
+        assert_eq!(map.get("B"), Some(3));
+    }
+}